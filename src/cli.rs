@@ -1,5 +1,9 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
 
+use crate::{model::DownMode, selection::Selection};
+
 #[derive(Parser)]
 #[command(author, about, version)]
 pub struct Options {
@@ -16,7 +20,22 @@ pub enum SubCommand {
     Up(Up),
 
     /// Shut down minikube environment
-    Down,
+    Down(Down),
+
+    /// Deploy the given releases again without touching minikube or port forwarding
+    Redeploy(Redeploy),
+
+    /// Uninstall the given releases
+    Uninstall(Uninstall),
+
+    /// Roll back a release to a previous revision and restart its port forwarding
+    Rollback(Rollback),
+
+    /// Forward the ports of the given releases, or of all releases, of the running cluster
+    Forward(Forward),
+
+    /// List all minikube profiles and the projects owning them
+    Profiles,
 }
 
 #[derive(Parser, Debug)]
@@ -24,4 +43,133 @@ pub struct Up {
     /// Don't deploy given helm charts and just start with the latest state
     #[arg(short, long)]
     pub no_deploy: bool,
+
+    /// Keep running and redeploy helm charts when the config file or their image build contexts change
+    #[arg(short, long)]
+    pub watch: bool,
+
+    /// Write the forwarded ports to the given file, as JSON if it ends with '.json', as '.env' file otherwise
+    #[arg(long, value_name = "FILE")]
+    pub forwards_file: Option<PathBuf>,
+
+    /// Recreate an already running cluster without asking if it differs from the config file
+    #[arg(long)]
+    pub recreate: bool,
+
+    /// Only deploy the given releases and the releases they depend on
+    #[arg(long, value_name = "RELEASE", num_args = 1..)]
+    pub only: Vec<String>,
+
+    /// Don't deploy the given releases, even if another release depends on them
+    #[arg(long, value_name = "RELEASE", num_args = 1..)]
+    pub skip: Vec<String>,
+
+    /// Only deploy releases with the given tag and the releases they depend on
+    #[arg(short, long, value_name = "TAG", num_args = 1..)]
+    pub tag: Vec<String>,
+}
+
+impl Up {
+    pub fn selection(&self) -> Selection {
+        Selection {
+            only: self.only.to_owned(),
+            skip: self.skip.to_owned(),
+            tags: self.tag.to_owned(),
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct Down {
+    /// Stop minikube, keeping images and persistent volumes
+    #[arg(long, conflicts_with_all = ["pause", "delete"])]
+    pub stop: bool,
+
+    /// Pause minikube, keeping images and persistent volumes
+    #[arg(long, conflicts_with_all = ["stop", "delete"])]
+    pub pause: bool,
+
+    /// Delete minikube including all images and persistent volumes
+    #[arg(long, conflicts_with_all = ["stop", "pause"])]
+    pub delete: bool,
+
+    /// Skip the 'pre_down' hooks, e.g. if one of them keeps failing
+    #[arg(long)]
+    pub no_hooks: bool,
+}
+
+impl Down {
+    /// The mode given on the command line, if any
+    pub fn mode(&self) -> Option<DownMode> {
+        if self.stop {
+            Some(DownMode::Stop)
+        } else if self.pause {
+            Some(DownMode::Pause)
+        } else if self.delete {
+            Some(DownMode::Delete)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct Redeploy {
+    /// Releases to redeploy
+    #[arg(value_name = "RELEASE", required = true)]
+    pub releases: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct Uninstall {
+    /// Releases to uninstall
+    #[arg(value_name = "RELEASE", required = true)]
+    pub releases: Vec<String>,
+
+    /// Delete the persistent volume claims of the releases as well
+    #[arg(long)]
+    pub delete_pvcs: bool,
+}
+
+#[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Forward {
+    #[clap(subcommand)]
+    pub action: Option<ForwardAction>,
+
+    /// Releases to forward, defaults to all releases
+    #[arg(value_name = "RELEASE")]
+    pub releases: Vec<String>,
+
+    /// Write the forwarded ports to the given file, as JSON if it ends with '.json', as '.env' file otherwise
+    #[arg(long, value_name = "FILE")]
+    pub forwards_file: Option<PathBuf>,
+
+    /// Run the port forwarding in the background, it keeps running after the terminal is closed
+    #[arg(short, long)]
+    pub detach: bool,
+
+    /// Runs as the background process started by '--detach'
+    #[arg(long, hide = true)]
+    pub supervisor: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ForwardAction {
+    /// Show whether port forwarding runs in the background and which ports it forwards
+    Status,
+
+    /// Stop the port forwarding running in the background
+    Stop,
+}
+
+#[derive(Parser, Debug)]
+pub struct Rollback {
+    /// Release to roll back
+    #[arg(value_name = "RELEASE")]
+    pub release: String,
+
+    /// Revision to roll back to, defaults to the previous revision
+    #[arg(long, value_name = "REVISION")]
+    pub to: Option<u32>,
 }
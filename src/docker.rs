@@ -0,0 +1,103 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::shutdown::SpawnTracked;
+
+pub struct Docker {
+    docker_binary_path: PathBuf,
+    environment: BTreeMap<String, String>,
+}
+
+impl Docker {
+    pub fn new(docker_binary_path: &PathBuf) -> Self {
+        Docker {
+            docker_binary_path: docker_binary_path.to_owned(),
+            environment: BTreeMap::new(),
+        }
+    }
+
+    /// Talk to another docker daemon, e.g. the one of minikube via the output of `minikube docker-env`
+    pub fn with_environment(&self, environment: &BTreeMap<String, String>) -> Self {
+        Docker {
+            docker_binary_path: self.docker_binary_path.to_owned(),
+            environment: environment.to_owned(),
+        }
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.docker_binary_path);
+        command.envs(&self.environment);
+        command
+    }
+
+    pub fn build(
+        &self,
+        image: &str,
+        context: &Path,
+        dockerfile: Option<&Path>,
+        build_args: &BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let mut command = self.command();
+        command.arg("build").arg("--tag").arg(image);
+
+        if let Some(dockerfile) = dockerfile {
+            command.arg("--file").arg(dockerfile);
+        }
+
+        for (key, value) in build_args {
+            command.arg("--build-arg").arg(format!("{}={}", key, value));
+        }
+
+        let status = command.arg(context).spawn_tracked()?.wait()?;
+
+        if !status.success() {
+            return Err(anyhow::anyhow!("Cannot build image '{}'.", image));
+        }
+
+        Ok(())
+    }
+
+    pub fn tag(&self, source: &str, target: &str) -> anyhow::Result<()> {
+        let status = self
+            .command()
+            .arg("tag")
+            .arg(source)
+            .arg(target)
+            .spawn_tracked()?
+            .wait()?;
+
+        if !status.success() {
+            return Err(anyhow::anyhow!(
+                "Cannot tag image '{}' as '{}'.",
+                source,
+                target
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Id of the image in the local docker daemon, if it exists
+    pub fn image_id(&self, image: &str) -> anyhow::Result<Option<String>> {
+        let output = self
+            .command()
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .arg("image")
+            .arg("inspect")
+            .arg("--format")
+            .arg("{{.Id}}")
+            .arg(image)
+            .spawn_tracked()?
+            .wait_with_output()?;
+
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        Ok(Some(String::from_utf8(output.stdout)?.trim().to_string()))
+    }
+}
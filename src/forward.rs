@@ -0,0 +1,525 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{BufRead, BufReader},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use colored::Colorize;
+use crossbeam_channel::{unbounded, Receiver};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    kubectl::Kubectl,
+    kubernetes::NativePortForward,
+    model::{Configuration, Helmchart, Port, PortTarget, RemotePort},
+    shutdown::{self, TrackedChild},
+};
+
+lazy_static! {
+    static ref FORWARDING_REGEX: Regex =
+        Regex::new(r"^Forwarding from 127\.0\.0\.1:(?P<local>[0-9]+) -> [0-9]+").unwrap();
+}
+
+/// How long to wait for kubectl to report the local ports
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often port forwards are checked for having exited
+pub const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A port forward running at least this long resets the backoff
+const STABLE_AFTER: Duration = Duration::from_secs(30);
+
+/// Called once the ports are forwarded, e.g. to run the 'post_up' hooks
+pub type OnForwarded<'a> = &'a dyn Fn(&[Forward]);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Forward {
+    pub release: String,
+    pub service: String,
+    /// The remote port as configured in `ports`, not the container port it resolved to
+    pub remote_port: RemotePort,
+    pub local_port: u16,
+    pub url: String,
+}
+
+/// A running port forward, either a `kubectl port-forward` child or a native one
+pub struct PortForward {
+    pub release: String,
+    pub service: String,
+    process: Process,
+}
+
+enum Process {
+    /// The output of kubectl is parsed for the forwarded local ports
+    Kubectl {
+        child: TrackedChild,
+        expected: usize,
+        reported: Receiver<u16>,
+    },
+    Native(NativePortForward),
+}
+
+impl PortForward {
+    /// `child` must have a piped stdout
+    pub fn kubectl(release: &str, service: &str, mut child: TrackedChild, expected: usize) -> Self {
+        let (sender, reported) = unbounded();
+
+        if let Some(stdout) = child.stdout.take() {
+            thread::spawn(move || {
+                // other lines, e.g. "Forwarding from [::1]:..." and "Handling connection for ...", are dropped
+                for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                    if let Some(captures) = FORWARDING_REGEX.captures(&line) {
+                        if let Ok(local) = captures["local"].parse::<u16>() {
+                            let _ = sender.send(local);
+                        }
+                    }
+                }
+            });
+        }
+
+        PortForward {
+            release: release.to_string(),
+            service: service.to_string(),
+            process: Process::Kubectl {
+                child,
+                expected,
+                reported,
+            },
+        }
+    }
+
+    pub fn native(release: &str, native: NativePortForward) -> Self {
+        PortForward {
+            release: release.to_string(),
+            service: native.name.to_string(),
+            process: Process::Native(native),
+        }
+    }
+
+    /// Wait until kubectl reported all forwarded ports, or the timeout is reached. The local ports are
+    /// reported in the order of `ports`.
+    pub fn forwards(&self, ports: &[Port]) -> Vec<Forward> {
+        let local_ports = match &self.process {
+            Process::Kubectl {
+                expected, reported, ..
+            } => {
+                let deadline = Instant::now() + REPORT_TIMEOUT;
+                let mut local_ports = vec![];
+
+                while local_ports.len() < *expected {
+                    match reported.recv_deadline(deadline) {
+                        Ok(local_port) => local_ports.push(local_port),
+                        Err(_) => break,
+                    }
+                }
+
+                local_ports
+            }
+            Process::Native(native) => native.local_ports.to_owned(),
+        };
+
+        ports
+            .iter()
+            .zip(local_ports)
+            .map(|(port, local_port)| Forward {
+                release: self.release.to_string(),
+                service: self.service.to_string(),
+                remote_port: port.remote.to_owned(),
+                local_port,
+                url: format!("http://localhost:{}", local_port),
+            })
+            .collect()
+    }
+
+    /// Why the port forward stopped, if it did
+    pub fn exited(&mut self) -> Option<String> {
+        match &mut self.process {
+            Process::Kubectl { child, .. } => match child.try_wait() {
+                Ok(None) => None,
+                Ok(Some(status)) => Some(status.to_string()),
+                Err(err) => Some(err.to_string()),
+            },
+            Process::Native(native) => native.exited(),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        match &mut self.process {
+            Process::Kubectl { child, .. } => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            Process::Native(native) => native.stop(),
+        }
+    }
+}
+
+/// Port forward to one target of a helm chart which is restarted with exponential backoff whenever
+/// it exits, e.g. because the pod restarted. Namespace, service and pod are resolved again on every restart.
+pub struct SupervisedPortForward {
+    helmchart: Helmchart,
+    target: PortTarget,
+    ports: Vec<Port>,
+    port_forward: Option<PortForward>,
+    forwards: Vec<Forward>,
+    started: Instant,
+    backoff: Duration,
+    next_attempt: Option<Instant>,
+}
+
+impl SupervisedPortForward {
+    /// Start one port forward per target of the ports of the helm chart
+    pub fn start_all(kubectl: &Kubectl, helmchart: &Helmchart) -> Vec<Self> {
+        let mut targets: BTreeMap<PortTarget, Vec<Port>> = BTreeMap::new();
+
+        for port in &helmchart.ports {
+            targets
+                .entry(port.target.to_owned())
+                .or_default()
+                .push(port.to_owned());
+        }
+
+        targets
+            .into_iter()
+            .map(|(target, ports)| SupervisedPortForward::start(kubectl, helmchart, target, ports))
+            .collect()
+    }
+
+    /// Like a reconnect, a port forward failing to start is retried later
+    fn start(
+        kubectl: &Kubectl,
+        helmchart: &Helmchart,
+        target: PortTarget,
+        ports: Vec<Port>,
+    ) -> Self {
+        let mut supervised = SupervisedPortForward {
+            helmchart: helmchart.to_owned(),
+            target,
+            ports,
+            port_forward: None,
+            forwards: vec![],
+            started: Instant::now(),
+            backoff: INITIAL_BACKOFF,
+            next_attempt: None,
+        };
+
+        match kubectl.spawn_port_forward(helmchart, &supervised.target, &supervised.ports) {
+            Ok(Some(port_forward)) => supervised.connected(port_forward),
+            Ok(None) => supervised.schedule_reconnect(),
+            Err(err) => {
+                println!("{}", err);
+                supervised.schedule_reconnect();
+            }
+        }
+
+        supervised
+    }
+
+    /// Forwards of the running kubectl, empty while reconnecting
+    pub fn forwards(&self) -> &[Forward] {
+        &self.forwards
+    }
+
+    /// Restart kubectl if it exited. Returns true after a reconnect, as the forwards changed.
+    pub fn supervise(&mut self, kubectl: &Kubectl) -> bool {
+        // the port forward was stopped on purpose
+        if shutdown::requested() {
+            return false;
+        }
+
+        if let Some(port_forward) = &mut self.port_forward {
+            let reason = match port_forward.exited() {
+                Some(reason) => reason,
+                None => {
+                    if self.started.elapsed() > STABLE_AFTER {
+                        self.backoff = INITIAL_BACKOFF;
+                    }
+
+                    return false;
+                }
+            };
+
+            port_forward.stop();
+
+            println!(
+                "{}",
+                format!(
+                    "Port forward of {} exited ({}).",
+                    self.description(),
+                    reason
+                )
+                .yellow()
+            );
+
+            self.port_forward = None;
+            self.forwards.clear();
+            self.schedule_reconnect();
+
+            return false;
+        }
+
+        match self.next_attempt {
+            Some(next_attempt) if Instant::now() >= next_attempt => {}
+            _ => return false,
+        }
+
+        println!("Reconnect port forward of {}...", self.description());
+
+        match kubectl.spawn_port_forward(&self.helmchart, &self.target, &self.ports) {
+            Ok(Some(port_forward)) => {
+                self.connected(port_forward);
+
+                true
+            }
+            Ok(None) => {
+                self.schedule_reconnect();
+
+                false
+            }
+            Err(err) => {
+                println!("{}", err);
+                self.schedule_reconnect();
+
+                false
+            }
+        }
+    }
+
+    fn connected(&mut self, port_forward: PortForward) {
+        self.forwards = port_forward.forwards(&self.ports);
+        self.port_forward = Some(port_forward);
+        self.started = Instant::now();
+        self.next_attempt = None;
+    }
+
+    fn schedule_reconnect(&mut self) {
+        println!(
+            "Reconnect port forward of {} in {}s.",
+            self.description(),
+            self.backoff.as_secs()
+        );
+
+        self.next_attempt = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    fn description(&self) -> String {
+        match &self.target {
+            PortTarget::Release => format!("'{}'", &self.helmchart.name),
+            PortTarget::Service(service) => {
+                format!("'{}' (service '{}')", &self.helmchart.name, service)
+            }
+            PortTarget::Selector(selector) => {
+                format!("'{}' (selector '{}')", &self.helmchart.name, selector)
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.forwards.clear();
+
+        if let Some(mut port_forward) = self.port_forward.take() {
+            port_forward.stop();
+        }
+    }
+}
+
+/// Forwards of all port forwards of a helm chart
+pub fn all_forwards(port_forwards: &[SupervisedPortForward]) -> Vec<Forward> {
+    port_forwards
+        .iter()
+        .flat_map(|port_forward| port_forward.forwards().to_vec())
+        .collect()
+}
+
+/// Local ports of all port forwards of a helm chart, by the remote port configured in `ports`
+pub fn local_ports(port_forwards: &[SupervisedPortForward]) -> BTreeMap<RemotePort, u16> {
+    port_forwards
+        .iter()
+        .flat_map(|port_forward| port_forward.forwards())
+        .map(|forward| (forward.remote_port.to_owned(), forward.local_port))
+        .collect()
+}
+
+pub fn print_forwards(forwards: &[Forward]) {
+    if forwards.is_empty() {
+        return;
+    }
+
+    println!();
+    println!("{}", "Forwarded ports".bold().underline());
+    println!("{:<24}{:<32}{:<8}URL", "RELEASE", "SERVICE", "REMOTE");
+
+    for forward in forwards {
+        println!(
+            "{:<24}{:<32}{:<8}{}",
+            forward.release,
+            forward.service,
+            forward.remote_port.to_string(),
+            forward.url
+        );
+    }
+
+    println!();
+}
+
+/// Write the forwards as JSON if the file ends with '.json', as '.env' file otherwise
+pub fn write_forwards(path: &Path, forwards: &[Forward]) -> anyhow::Result<()> {
+    let content = if path.extension().map(|extension| extension == "json") == Some(true) {
+        serde_json::to_string_pretty(forwards)?
+    } else {
+        forward_variables(forwards)
+            .into_iter()
+            .map(|(name, value)| format!("{}={}\n", name, value))
+            .collect::<String>()
+    };
+
+    fs::write(path, content)?;
+
+    Ok(())
+}
+
+/// Forwards are identified by release and remote port, e.g. by `forward_variables` and the health checks,
+/// so a remote port must not be forwarded twice per release, even from different services. The variables
+/// of different forwards must not end up with the same name either.
+pub fn validate(configuration: &Configuration) -> anyhow::Result<()> {
+    let mut prefixes = BTreeMap::new();
+
+    for helmchart in configuration.helmchart.iter().flatten() {
+        let mut remote_ports = BTreeSet::new();
+
+        for port in &helmchart.ports {
+            if !remote_ports.insert(&port.remote) {
+                return Err(anyhow::anyhow!(
+                    "The remote port {} of helm chart '{}' is forwarded more than once. Please forward it only once.",
+                    &port.remote,
+                    &helmchart.name
+                ));
+            }
+
+            let prefix = variable_prefix(&helmchart.name, &port.remote);
+
+            if let Some((name, remote)) =
+                prefixes.insert(prefix.to_string(), (&helmchart.name, &port.remote))
+            {
+                return Err(anyhow::anyhow!(
+                    "The remote port {} of helm chart '{}' and the remote port {} of helm chart '{}' would both be written to '{}_PORT'. Please rename one of them.",
+                    remote,
+                    name,
+                    &port.remote,
+                    &helmchart.name,
+                    prefix
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// `<RELEASE>_<REMOTE PORT>_PORT` and `<RELEASE>_<REMOTE PORT>_URL` of each forward
+pub fn forward_variables(forwards: &[Forward]) -> Vec<(String, String)> {
+    forwards
+        .iter()
+        .flat_map(|forward| {
+            let prefix = variable_prefix(&forward.release, &forward.remote_port);
+
+            [
+                (format!("{}_PORT", prefix), forward.local_port.to_string()),
+                (format!("{}_URL", prefix), forward.url.to_string()),
+            ]
+        })
+        .collect()
+}
+
+fn variable_prefix(release: &str, remote_port: &RemotePort) -> String {
+    format!("{}_{}", release, remote_port)
+        .to_uppercase()
+        .chars()
+        .map(|character| {
+            if character.is_ascii_alphanumeric() {
+                character
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::fixtures::{configuration, helmchart_toml};
+
+    fn forward(release: &str, remote_port: RemotePort, local_port: u16) -> Forward {
+        Forward {
+            release: release.to_string(),
+            service: release.to_string(),
+            remote_port,
+            local_port,
+            url: format!("http://localhost:{}", local_port),
+        }
+    }
+
+    #[test]
+    fn env_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("forwards.env");
+
+        write_forwards(
+            &path,
+            &[
+                forward("helm-chart.1", RemotePort::Number(80), 40001),
+                forward("db", RemotePort::Name("metrics".into()), 40002),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "HELM_CHART_1_80_PORT=40001\nHELM_CHART_1_80_URL=http://localhost:40001\nDB_METRICS_PORT=40002\nDB_METRICS_URL=http://localhost:40002\n"
+        );
+    }
+
+    #[test]
+    fn remote_ports_are_forwarded_once_per_release() {
+        let configuration = |ports: &str| {
+            configuration(&helmchart_toml("app", &format!("ports = {}", ports))).unwrap()
+        };
+
+        assert!(validate(&configuration(r#"[80, { remote = 81, service = "b" }]"#)).is_ok());
+        assert!(validate(&configuration(r#"[80, { remote = 80, service = "b" }]"#)).is_err());
+        assert!(validate(&configuration(
+            r#"["http", { remote = "http", selector = "a=b" }]"#
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn variable_names_are_unique() {
+        let configuration = |toml: &str| validate(&configuration(toml).unwrap());
+
+        assert!(configuration(&helmchart_toml(
+            "app",
+            r#"ports = [80, { remote = 8080, selector = "a=b" }]"#
+        ))
+        .is_ok());
+
+        let err = configuration(&format!(
+            "{}{}",
+            helmchart_toml("my-app", "ports = [80]"),
+            helmchart_toml("my_app", "ports = [80]")
+        ))
+        .unwrap_err();
+
+        assert!(err.to_string().contains("'MY_APP_80_PORT'"), "{}", err);
+    }
+}
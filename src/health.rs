@@ -0,0 +1,297 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use colored::Colorize;
+use crossbeam_channel::{bounded, Sender};
+
+use crate::{
+    kubectl::Kubectl,
+    model::{HealthCheck, Helmchart, Probe, RemotePort},
+    shutdown::{self, SpawnTracked},
+};
+
+/// Pause between two attempts of a failing health check
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A TCP connection closed within this time was refused by the pod behind the port forward
+const TCP_SETTLE_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct HealthCheckResult {
+    pub release: String,
+    pub check: String,
+    pub passed: bool,
+    pub attempts: u32,
+    /// Outcome of the last attempt
+    pub details: String,
+}
+
+/// Run all health checks of the helm chart one after another, `local_ports` are the forwarded ports
+pub fn check(
+    kubectl: &Kubectl,
+    helmchart: &Helmchart,
+    local_ports: &BTreeMap<RemotePort, u16>,
+) -> Vec<HealthCheckResult> {
+    helmchart
+        .health_check
+        .iter()
+        .map(|health_check| run(kubectl, helmchart, health_check, local_ports))
+        .collect()
+}
+
+/// Run the health checks of the helm chart in the background and send the results along with the release
+pub fn spawn(
+    kubectl: &Kubectl,
+    helmchart: &Helmchart,
+    local_ports: BTreeMap<RemotePort, u16>,
+    sender: &Sender<(String, Vec<HealthCheckResult>)>,
+) {
+    let kubectl = kubectl.clone();
+    let helmchart = helmchart.clone();
+    let sender = sender.clone();
+
+    thread::spawn(move || {
+        let results = check(&kubectl, &helmchart, &local_ports);
+
+        let _ = sender.send((helmchart.name.to_string(), results));
+    });
+}
+
+/// All health checks of the helm chart failed without being run, e.g. because its ports aren't forwarded
+pub fn not_run(helmchart: &Helmchart, reason: &str) -> Vec<HealthCheckResult> {
+    helmchart
+        .health_check
+        .iter()
+        .map(|health_check| HealthCheckResult {
+            release: helmchart.name.to_string(),
+            check: health_check.probe.to_string(),
+            passed: false,
+            attempts: 0,
+            details: reason.to_string(),
+        })
+        .collect()
+}
+
+fn run(
+    kubectl: &Kubectl,
+    helmchart: &Helmchart,
+    health_check: &HealthCheck,
+    local_ports: &BTreeMap<RemotePort, u16>,
+) -> HealthCheckResult {
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+
+        let outcome = attempt(kubectl, helmchart, health_check, local_ports);
+        let last_attempt = attempts > health_check.retries || shutdown::requested();
+
+        let (passed, details) = match outcome {
+            Ok(details) => (true, details),
+            Err(err) if last_attempt => (false, err.to_string()),
+            Err(_) => {
+                thread::sleep(RETRY_INTERVAL);
+
+                continue;
+            }
+        };
+
+        return HealthCheckResult {
+            release: helmchart.name.to_string(),
+            check: health_check.probe.to_string(),
+            passed,
+            attempts,
+            details,
+        };
+    }
+}
+
+fn attempt(
+    kubectl: &Kubectl,
+    helmchart: &Helmchart,
+    health_check: &HealthCheck,
+    local_ports: &BTreeMap<RemotePort, u16>,
+) -> anyhow::Result<String> {
+    let timeout = Duration::from_secs(health_check.timeout);
+
+    match &health_check.probe {
+        Probe::Http { path, port, status } => {
+            http_get(local_port(local_ports, port)?, path, *status, timeout)
+        }
+        Probe::Tcp { port } => tcp_connect(local_port(local_ports, port)?, timeout),
+        Probe::Exec {
+            command,
+            selector,
+            container,
+        } => exec(
+            kubectl.exec_command(
+                &helmchart.name,
+                selector.as_deref(),
+                container.as_deref(),
+                command,
+            )?,
+            timeout,
+        ),
+    }
+}
+
+fn local_port(local_ports: &BTreeMap<RemotePort, u16>, port: &RemotePort) -> anyhow::Result<u16> {
+    local_ports
+        .get(port)
+        .copied()
+        .ok_or_else(|| anyhow::anyhow!("port {} isn't forwarded", port))
+}
+
+fn http_get(
+    local_port: u16,
+    path: &str,
+    expected_status: u16,
+    timeout: Duration,
+) -> anyhow::Result<String> {
+    let mut stream =
+        TcpStream::connect_timeout(&SocketAddr::from(([127, 0, 0, 1], local_port)), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n",
+        path, local_port
+    )?;
+
+    // only the status line matters, e.g. "HTTP/1.1 200 OK"
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| anyhow::anyhow!("no HTTP response"))?;
+
+    if status == expected_status {
+        Ok(format!("HTTP {}", status))
+    } else {
+        Err(anyhow::anyhow!(
+            "HTTP {}, expected {}",
+            status,
+            expected_status
+        ))
+    }
+}
+
+/// The local end of a port forward accepts every connection, so the connection must also stay open
+/// (or receive data) for a moment
+fn tcp_connect(local_port: u16, timeout: Duration) -> anyhow::Result<String> {
+    let mut stream =
+        TcpStream::connect_timeout(&SocketAddr::from(([127, 0, 0, 1], local_port)), timeout)?;
+    stream.set_read_timeout(Some(TCP_SETTLE_TIME))?;
+
+    match stream.read(&mut [0; 1]) {
+        Ok(0) => Err(anyhow::anyhow!("connection closed")),
+        Ok(_) => Ok("connected".to_string()),
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Ok("connected".to_string())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn exec(mut command: Command, timeout: Duration) -> anyhow::Result<String> {
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn_tracked()?;
+    let pid = child.id();
+
+    // reading the output in another thread keeps a chatty command from blocking on a full pipe
+    let (sender, receiver) = bounded(1);
+
+    thread::spawn(move || {
+        let _ = sender.send(child.wait_with_output());
+    });
+
+    let output = match receiver.recv_timeout(timeout) {
+        Ok(output) => output?,
+        Err(_) => {
+            shutdown::signal(pid, libc::SIGKILL)?;
+
+            return Err(anyhow::anyhow!("timed out after {}s", timeout.as_secs()));
+        }
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // the last line of the output tells most, stderr for failures and stdout otherwise
+    let (first, second) = if output.status.success() {
+        (&stdout, &stderr)
+    } else {
+        (&stderr, &stdout)
+    };
+
+    let last_line = last_line(first)
+        .or_else(|| last_line(second))
+        .unwrap_or_default();
+
+    match (output.status.success(), last_line.is_empty()) {
+        (true, true) => Ok(output.status.to_string()),
+        (true, false) => Ok(last_line),
+        (false, true) => Err(anyhow::anyhow!("{}", output.status)),
+        (false, false) => Err(anyhow::anyhow!("{}: {}", output.status, last_line)),
+    }
+}
+
+fn last_line(output: &str) -> Option<String> {
+    output
+        .lines()
+        .rfind(|line| !line.trim().is_empty())
+        .map(|line| line.trim().to_string())
+}
+
+pub fn print_results(results: &[HealthCheckResult]) {
+    if results.is_empty() {
+        return;
+    }
+
+    println!();
+    println!("{}", "Health checks".bold().underline());
+    println!("{:<24}{:<32}{:<8}DETAILS", "RELEASE", "CHECK", "RESULT");
+
+    for result in results {
+        let outcome = if result.passed {
+            format!("{:<8}", "passed").green()
+        } else {
+            format!("{:<8}", "failed").red()
+        };
+
+        let details = match result.attempts {
+            0 => result.details.to_string(),
+            1 => format!("{} (1 attempt)", result.details),
+            attempts => format!("{} ({} attempts)", result.details, attempts),
+        };
+
+        println!(
+            "{:<24}{:<32}{}{}",
+            result.release, result.check, outcome, details
+        );
+    }
+
+    let failed = results.iter().filter(|result| !result.passed).count();
+
+    if failed > 0 {
+        println!(
+            "{}",
+            format!("{} of {} health checks failed.", failed, results.len()).yellow()
+        );
+    }
+
+    println!();
+}
@@ -1,23 +1,156 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::BTreeMap,
     path::PathBuf,
     process::{Command, Stdio},
 };
 
 use lazy_static::lazy_static;
 use regex::Regex;
-use which::which;
+use serde_derive::Deserialize;
 
-use crate::model::{HelmChartRepo, Helmchart};
+use crate::{
+    model::{Configuration, HelmChartRepo, HelmOptions, Helmchart},
+    shutdown::SpawnTracked,
+};
 
 lazy_static! {
     static ref ENV_VAR_REGEX: Regex = Regex::new(r"\$\{env\.(?P<env_var>[a-zA-Z0-9_]+)\}").unwrap();
+    static ref DURATION_REGEX: Regex =
+        Regex::new(r"^([0-9]+(\.[0-9]+)?(ns|us|µs|ms|s|m|h))+$").unwrap();
+}
+
+const FINGERPRINT_PREFIX: &str = "kleinwuerfel values ";
+
+/// Description helm gives to revisions created by 'helm rollback'
+const ROLLBACK_PREFIX: &str = "Rollback to ";
+
+/// Flags kleinwuerfel sets itself, which therefore must not show up in `helm_args`
+const CONTROLLED_REPO_FLAGS: &[&str] = &[
+    "--username",
+    "--password",
+    "--kube-context",
+    "--force-update",
+];
+const CONTROLLED_UPGRADE_FLAGS: &[&str] = &[
+    "--username",
+    "--password",
+    "--kube-context",
+    "--install",
+    "-i",
+    "--description",
+    "--set",
+    "--timeout",
+    "--wait",
+    "--wait-for-jobs",
+    "--atomic",
+    "--cleanup-on-fail",
+];
+
+pub fn validate(configuration: &Configuration) -> anyhow::Result<()> {
+    validate_timeout(&configuration.helm, "the '[helm]' block")?;
+
+    for helm_chart_repo in configuration.helm_chart_repo.iter().flatten() {
+        validate_helm_args(
+            &helm_chart_repo.helm_args,
+            "helm_args",
+            CONTROLLED_REPO_FLAGS,
+            &format!("helm chart repo '{}'", &helm_chart_repo.name),
+        )?;
+        validate_helm_args(
+            &helm_chart_repo.login_args,
+            "login_args",
+            CONTROLLED_REPO_FLAGS,
+            &format!("helm chart repo '{}'", &helm_chart_repo.name),
+        )?;
+    }
+
+    for helmchart in configuration.helmchart.iter().flatten() {
+        validate_helm_args(
+            &helmchart.helm_args,
+            "helm_args",
+            CONTROLLED_UPGRADE_FLAGS,
+            &format!("helm chart '{}'", &helmchart.name),
+        )?;
+        validate_timeout(
+            &helmchart.helm,
+            &format!("helm chart '{}'", &helmchart.name),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn validate_timeout(options: &HelmOptions, context: &str) -> anyhow::Result<()> {
+    match &options.timeout {
+        Some(timeout) if !DURATION_REGEX.is_match(timeout) => Err(anyhow::anyhow!(
+            "Invalid timeout '{}' of {}. Please provide a duration like '90s' or '10m'.",
+            timeout,
+            context
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_helm_args(
+    helm_args: &[String],
+    field: &str,
+    controlled: &[&str],
+    context: &str,
+) -> anyhow::Result<()> {
+    for helm_arg in helm_args {
+        let flag = helm_arg.split('=').next().unwrap_or_default();
+
+        if controlled.contains(&flag) {
+            return Err(anyhow::anyhow!(
+                "The argument '{}' in '{}' of {} is controlled by kleinwuerfel. Please remove it.",
+                helm_arg,
+                field,
+                context
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Revision {
+    pub revision: u32,
+    pub updated: String,
+    pub status: String,
+    pub chart: String,
+    pub app_version: String,
+    pub description: String,
+}
+
+impl Revision {
+    /// Fingerprint of the values of the revision. 'helm rollback' can't set a description, so a rollback
+    /// revision gets the fingerprint of the revision it rolled back to, as found in the history.
+    pub fn fingerprint<'a>(&'a self, history: &'a [Revision]) -> Option<&'a str> {
+        if let Some(fingerprint) = self.description.strip_prefix(FINGERPRINT_PREFIX) {
+            return Some(fingerprint);
+        }
+
+        let target = self
+            .description
+            .strip_prefix(ROLLBACK_PREFIX)?
+            .trim()
+            .parse::<u32>()
+            .ok()?;
+
+        // rollback targets are older, which keeps chains of rollbacks from looping
+        history
+            .iter()
+            .find(|revision| revision.revision == target && target < self.revision)?
+            .fingerprint(history)
+    }
 }
 
 pub struct Helm {
     helm_chart_repo: HelmChartRepo,
     default_values: BTreeMap<String, String>,
     helm_binary_path: PathBuf,
+    kube_context: String,
 }
 
 impl Helm {
@@ -25,11 +158,13 @@ impl Helm {
         helm_chart_repo: &HelmChartRepo,
         default_values: &BTreeMap<String, String>,
         helm_binary_path: &PathBuf,
+        kube_context: &str,
     ) -> Self {
         Helm {
             helm_chart_repo: helm_chart_repo.to_owned(),
             default_values: default_values.to_owned(),
             helm_binary_path: helm_binary_path.to_owned(),
+            kube_context: kube_context.to_string(),
         }
     }
 
@@ -60,6 +195,9 @@ impl Helm {
     pub fn initial_arguments(&self) -> anyhow::Result<Vec<String>> {
         let mut arguments = vec![];
 
+        arguments.push("--kube-context".to_string());
+        arguments.push(self.kube_context.to_string());
+
         if let (Some(username), Some(password)) = (
             self.helm_chart_repo.username.to_owned(),
             self.helm_chart_repo.password.to_owned(),
@@ -87,12 +225,13 @@ impl Helm {
         arguments.push("registry".to_string());
         arguments.push("login".to_string());
         arguments.push(helm_repo_url.to_string());
+        arguments.extend(self.helm_chart_repo.login_args.to_owned());
 
+        // helm's error tells why the login failed
         let status = Command::new(self.helm_binary_path.as_path())
-            .stderr(Stdio::null())
             .stdout(Stdio::null())
             .args(&arguments)
-            .spawn()?
+            .spawn_tracked()?
             .wait_with_output()?;
 
         Ok(status.status.success())
@@ -106,65 +245,292 @@ impl Helm {
         arguments.push("--force-update".to_string());
         arguments.push(helm_chart_repo.name.to_string());
         arguments.push(helm_chart_repo.url.to_string());
+        arguments.extend(helm_chart_repo.helm_args.to_owned());
 
         Command::new(self.helm_binary_path.as_path())
             .args(&arguments)
-            .spawn()?
+            .spawn_tracked()?
             .wait()?;
 
         Ok(())
     }
 
-    pub fn upgrade(&self, chart_repo: &String, helmchart: &Helmchart) -> anyhow::Result<()> {
-        let mut arguments = self.initial_arguments()?;
-
-        arguments.push("upgrade".to_string());
-        arguments.push("--install".to_string());
-
-        let mut all_values = HashMap::new();
-
-        for (key, value) in &self.default_values {
-            all_values.insert(key, value);
-        }
+    /// Default values and values of the helm chart as written in the config file
+    fn configured_values(&self, helmchart: &Helmchart) -> BTreeMap<String, String> {
+        let mut all_values = self.default_values.to_owned();
 
         for (key, value) in &helmchart.values {
-            all_values.insert(key, value);
+            all_values.insert(key.to_owned(), value.to_owned());
         }
 
-        let mut values = HashMap::new();
+        all_values
+    }
 
-        for (key, value) in all_values {
+    pub fn values(&self, helmchart: &Helmchart) -> anyhow::Result<BTreeMap<String, String>> {
+        let mut values = BTreeMap::new();
+
+        for (key, value) in &self.configured_values(helmchart) {
             let value = self
                 .replace_with_env_var(value)?
                 .unwrap_or_else(|| value.to_owned());
 
-            values.insert(key, value);
+            values.insert(key.to_owned(), value);
         }
 
-        if !values.is_empty() {
-            for (key, value) in values {
-                arguments.push("--set".to_string());
-                arguments.push(format!("{}={}", key, value.replace(',', r"\,")));
+        Ok(values)
+    }
+
+    /// FNV-1a hash over the values, stable across runs and Rust versions. It's written to the release history,
+    /// so it's taken before `${env.…}` is replaced, keeping secrets from the environment out of it.
+    pub fn fingerprint(values: &BTreeMap<String, String>) -> String {
+        let mut hash: u64 = 0xcbf29ce484222325;
+
+        for (key, value) in values {
+            for byte in key.bytes().chain([b'=']).chain(value.bytes()).chain([0]) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
             }
         }
 
+        format!("{:016x}", hash)
+    }
+
+    fn upgrade_arguments(options: &HelmOptions) -> Vec<String> {
+        let mut arguments = vec![];
+
+        if let Some(timeout) = &options.timeout {
+            arguments.push("--timeout".to_string());
+            arguments.push(timeout.to_string());
+        }
+
+        // helm waits for the resources anyway when '--atomic' is set
+        if options.wait.unwrap_or(true) {
+            arguments.push("--wait".to_string());
+        }
+
+        if options.wait_for_jobs.unwrap_or_default() {
+            arguments.push("--wait-for-jobs".to_string());
+        }
+
+        if options.atomic.unwrap_or_default() {
+            arguments.push("--atomic".to_string());
+        }
+
+        if options.cleanup_on_fail.unwrap_or_default() {
+            arguments.push("--cleanup-on-fail".to_string());
+        }
+
+        arguments
+    }
+
+    pub fn upgrade(
+        &self,
+        chart_repo: &String,
+        helmchart: &Helmchart,
+        options: &HelmOptions,
+    ) -> anyhow::Result<()> {
+        let mut arguments = self.initial_arguments()?;
+
+        arguments.push("upgrade".to_string());
+        arguments.push("--install".to_string());
+
+        arguments.push("--description".to_string());
+        arguments.push(format!(
+            "{}{}",
+            FINGERPRINT_PREFIX,
+            Helm::fingerprint(&self.configured_values(helmchart))
+        ));
+
+        let values = self.values(helmchart)?;
+
+        for (key, value) in values {
+            arguments.push("--set".to_string());
+            arguments.push(format!("{}={}", key, value.replace(',', r"\,")));
+        }
+
         arguments.push(helmchart.name.to_string());
         arguments.push(format!("{}/{}", &chart_repo, &helmchart.name));
-        arguments.push("--wait".to_string());
+        arguments.extend(Helm::upgrade_arguments(options));
+        arguments.extend(helmchart.helm_args.to_owned());
 
-        Command::new(self.helm_binary_path.as_path())
+        let status = Command::new(self.helm_binary_path.as_path())
             .args(&arguments)
-            .spawn()?
+            .spawn_tracked()?
+            .wait()?;
+
+        if !status.success() {
+            return Err(anyhow::anyhow!(
+                "Cannot upgrade helm chart '{}'.",
+                &helmchart.name
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn uninstall(
+        helm_binary_path: &PathBuf,
+        kube_context: &str,
+        release: &str,
+    ) -> anyhow::Result<()> {
+        let status = Command::new(helm_binary_path)
+            .arg("--kube-context")
+            .arg(kube_context)
+            .arg("uninstall")
+            .arg(release)
+            .spawn_tracked()?
             .wait()?;
 
+        if !status.success() {
+            return Err(anyhow::anyhow!("Cannot uninstall release '{}'.", release));
+        }
+
         Ok(())
     }
 
-    pub fn list() -> anyhow::Result<()> {
-        let helm_binary = which("helm")?;
+    pub fn history(
+        helm_binary_path: &PathBuf,
+        kube_context: &str,
+        release: &str,
+    ) -> anyhow::Result<Vec<Revision>> {
+        let output = Command::new(helm_binary_path)
+            .stdout(Stdio::piped())
+            .arg("--kube-context")
+            .arg(kube_context)
+            .arg("history")
+            .arg(release)
+            .arg("--output")
+            .arg("json")
+            .spawn_tracked()?
+            .wait_with_output()?;
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Cannot read history of release '{}'.",
+                release
+            ));
+        }
+
+        Ok(serde_json::from_slice(&output.stdout)?)
+    }
+
+    pub fn rollback(
+        helm_binary_path: &PathBuf,
+        kube_context: &str,
+        release: &str,
+        revision: Option<u32>,
+    ) -> anyhow::Result<()> {
+        // revision 0 makes helm roll back to the previous revision
+        let status = Command::new(helm_binary_path)
+            .arg("--kube-context")
+            .arg(kube_context)
+            .arg("rollback")
+            .arg(release)
+            .arg(revision.unwrap_or_default().to_string())
+            .arg("--wait")
+            .spawn_tracked()?
+            .wait()?;
 
-        Command::new(helm_binary).arg("list").spawn()?.wait()?;
+        if !status.success() {
+            return Err(anyhow::anyhow!("Cannot roll back release '{}'.", release));
+        }
 
         Ok(())
     }
+
+    pub fn list(helm_binary_path: &PathBuf, kube_context: &str) -> anyhow::Result<()> {
+        Command::new(helm_binary_path)
+            .arg("--kube-context")
+            .arg(kube_context)
+            .arg("list")
+            .spawn_tracked()?
+            .wait()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::fixtures::{configuration, helmchart_toml};
+
+    fn timeout(timeout: &str) -> anyhow::Result<()> {
+        validate(
+            &configuration(&helmchart_toml(
+                "app",
+                &format!("helm = {{ timeout = \"{}\" }}", timeout),
+            ))
+            .unwrap(),
+        )?;
+
+        validate(&configuration(&format!("[helm]\ntimeout = \"{}\"", timeout)).unwrap())
+    }
+
+    fn revision(revision: u32, description: &str) -> Revision {
+        Revision {
+            revision,
+            updated: String::new(),
+            status: String::new(),
+            chart: String::new(),
+            app_version: String::new(),
+            description: description.to_string(),
+        }
+    }
+
+    #[test]
+    fn durations() {
+        for valid in ["90s", "10m", "1h30m", "1.5h", "500ms"] {
+            assert!(timeout(valid).is_ok(), "{}", valid);
+        }
+
+        for invalid in ["", "10", "m", "10 m", "10min", "-5s"] {
+            assert!(timeout(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        let values = BTreeMap::from([
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "${env.SECRET}".to_string()),
+        ]);
+
+        // written to the release history, so it must not change between versions of kleinwuerfel
+        assert_eq!(Helm::fingerprint(&values), "05a1bfed4ac2315c");
+
+        let mut changed = values.clone();
+        changed.insert("a".to_string(), "2".to_string());
+
+        assert_ne!(Helm::fingerprint(&values), Helm::fingerprint(&changed));
+    }
+
+    #[test]
+    fn fingerprint_of_rollback_revisions() {
+        let history = vec![
+            revision(1, "kleinwuerfel values 1111111111111111"),
+            revision(2, "kleinwuerfel values 2222222222222222"),
+            revision(3, "Rollback to 1"),
+            revision(4, "Rollback to 3"),
+            revision(5, "Upgrade complete"),
+            revision(6, "Rollback to 6"),
+        ];
+
+        let fingerprints = history
+            .iter()
+            .map(|revision| revision.fingerprint(&history))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            fingerprints,
+            [
+                Some("1111111111111111"),
+                Some("2222222222222222"),
+                Some("1111111111111111"),
+                Some("1111111111111111"),
+                None,
+                None,
+            ]
+        );
+    }
 }
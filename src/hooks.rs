@@ -0,0 +1,229 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use crate::{
+    forward::{forward_variables, Forward},
+    kubectl::Kubectl,
+    model::{Configuration, Hook, HookAction},
+    shutdown::SpawnTracked,
+};
+
+/// Fail early if a hook refers to an unknown release, or runs in the cluster without any release.
+/// 'exec' can't run in a release before its first install, so 'pre_deploy' hooks can't exec into their own.
+/// 'pre_up' runs before minikube is bootstrapped, so its hooks can't run in the cluster at all.
+pub fn validate(configuration: &Configuration) -> anyhow::Result<()> {
+    let helmcharts = configuration.helmchart.to_owned().unwrap_or_default();
+
+    if let Some(hook) = configuration
+        .pre_up
+        .iter()
+        .find(|hook| hook.action.in_cluster())
+    {
+        return Err(anyhow::anyhow!(
+            "The hook '{}' of 'pre_up' runs in the cluster, which isn't bootstrapped yet. Please use a local command or a 'post_up' hook instead.",
+            hook.action
+        ));
+    }
+
+    let mut stages = vec![
+        ("'pre_up'".to_string(), &configuration.pre_up, None, false),
+        ("'post_up'".to_string(), &configuration.post_up, None, false),
+        (
+            "'pre_down'".to_string(),
+            &configuration.pre_down,
+            None,
+            false,
+        ),
+    ];
+
+    for helmchart in &helmcharts {
+        stages.push((
+            format!("'pre_deploy' of helm chart '{}'", &helmchart.name),
+            &helmchart.pre_deploy,
+            Some(helmchart.name.as_str()),
+            true,
+        ));
+        stages.push((
+            format!("'post_deploy' of helm chart '{}'", &helmchart.name),
+            &helmchart.post_deploy,
+            Some(helmchart.name.as_str()),
+            false,
+        ));
+    }
+
+    for (stage, hooks, default_release, before_deploy) in stages {
+        for hook in hooks {
+            match hook.release.as_deref().or(default_release) {
+                Some(release) if !helmcharts.iter().any(|helmchart| helmchart.name == release) => {
+                    return Err(anyhow::anyhow!(
+                        "The hook '{}' of {} refers to the unknown release '{}'.",
+                        hook.action,
+                        stage,
+                        release
+                    ))
+                }
+                Some(release)
+                    if before_deploy
+                        && default_release == Some(release)
+                        && matches!(hook.action, HookAction::Exec { .. }) =>
+                {
+                    return Err(anyhow::anyhow!(
+                        "The hook '{}' of {} can't exec into its own release, which doesn't run before its first install. Please use a 'job' instead.",
+                        hook.action,
+                        stage
+                    ))
+                }
+                None if hook.action.in_cluster() => {
+                    return Err(anyhow::anyhow!(
+                        "The hook '{}' of {} runs in the cluster and needs a 'release'.",
+                        hook.action,
+                        stage
+                    ))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the hooks one after another, the first failing hook stops the stage. `release` is the helm chart
+/// of per-chart hooks, `forwards` are the ports forwarded so far.
+pub fn run(
+    kubectl: &Kubectl,
+    project_directory: &Path,
+    profile: &str,
+    stage: &str,
+    hooks: &[Hook],
+    release: Option<&str>,
+    forwards: &[Forward],
+) -> anyhow::Result<()> {
+    for hook in hooks {
+        println!("Run {} hook '{}'", stage, hook.action);
+
+        let release = hook.release.as_deref().or(release);
+        let failed = |reason: String| {
+            anyhow::anyhow!("The {} hook '{}' failed: {}", stage, hook.action, reason)
+        };
+
+        let mut variables = BTreeMap::new();
+        variables.insert("KLEINWUERFEL_PROFILE".to_string(), profile.to_string());
+
+        if let Some(release) = release {
+            variables.insert("KLEINWUERFEL_RELEASE".to_string(), release.to_string());
+
+            // the release might not be installed yet, e.g. for the first 'pre_deploy'
+            let namespace = kubectl.release_namespace(release);
+
+            match (&hook.action, namespace) {
+                (_, Ok(namespace)) => {
+                    variables.insert("KLEINWUERFEL_NAMESPACE".to_string(), namespace);
+                }
+                // local hooks don't need the cluster
+                (HookAction::Local(_), Err(_)) => {}
+                (_, Err(err)) => return Err(failed(err.to_string())),
+            }
+        }
+
+        let status = match &hook.action {
+            HookAction::Local(command) => {
+                variables.extend(forward_variables(forwards));
+                variables.extend(hook.env.to_owned());
+
+                // a process group of its own terminates the processes started by 'sh -c' on shutdown as well.
+                // It's no foreground process group of the terminal anymore, so reading from it would stop the hook.
+                Command::new(&command[0])
+                    .args(&command[1..])
+                    .current_dir(project_directory)
+                    .envs(&variables)
+                    .stdin(Stdio::null())
+                    .spawn_tracked_group()
+                    .map_err(|err| failed(err.to_string()))?
+                    .wait()?
+            }
+            HookAction::Exec {
+                command,
+                selector,
+                container,
+            } => {
+                // the local ports of the forwards mean nothing inside of the cluster
+                variables.extend(hook.env.to_owned());
+
+                // 'kubectl exec' doesn't pass environment variables on, so 'env' sets them in the container
+                let command = ["env".to_string()]
+                    .into_iter()
+                    .chain(
+                        variables
+                            .iter()
+                            .map(|(name, value)| format!("{}={}", name, value)),
+                    )
+                    .chain(command.iter().cloned())
+                    .collect::<Vec<String>>();
+
+                kubectl
+                    .exec_command(
+                        release.unwrap_or_default(),
+                        selector.as_deref(),
+                        container.as_deref(),
+                        &command,
+                    )
+                    .map_err(|err| failed(err.to_string()))?
+                    .spawn_tracked()?
+                    .wait()?
+            }
+            HookAction::Job(manifest) => {
+                kubectl
+                    .run_job(
+                        release.unwrap_or_default(),
+                        &project_directory.join(manifest),
+                        Duration::from_secs(hook.timeout),
+                    )
+                    .map_err(|err| failed(err.to_string()))?;
+
+                continue;
+            }
+        };
+
+        if !status.success() {
+            return Err(failed(status.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::fixtures::{configuration, helmchart_toml};
+
+    #[test]
+    fn pre_up_runs_locally() {
+        for hook in [
+            r#"{ exec = ["true"], release = "app" }"#,
+            r#"{ job = "job.yaml", release = "app" }"#,
+        ] {
+            let configuration = configuration(&format!(
+                "pre_up = [{}]\n{}",
+                hook,
+                helmchart_toml("app", "")
+            ))
+            .unwrap();
+
+            let err = validate(&configuration).unwrap_err().to_string();
+            assert!(err.contains("of 'pre_up' runs in the cluster"), "{}", err);
+        }
+
+        let configuration = configuration(&format!(
+            "pre_up = [\"make seed\"]\n{}",
+            helmchart_toml("app", "")
+        ))
+        .unwrap();
+        assert!(validate(&configuration).is_ok());
+    }
+}
@@ -0,0 +1,345 @@
+use std::{fs, path::PathBuf};
+
+const DEFAULT_HOSTS_FILE: &str = "/etc/hosts";
+
+/// Block of the hosts file kleinwuerfel manages for a profile, between a begin and an end marker.
+/// Everything outside of the block is left untouched.
+pub struct HostsFile {
+    path: PathBuf,
+    profile: String,
+}
+
+/// Lines of the hosts file and whether the last one ends with a newline
+struct Content {
+    lines: Vec<String>,
+    trailing_newline: bool,
+}
+
+impl HostsFile {
+    /// Uses `$KLEINWUERFEL_HOSTS_FILE` instead of /etc/hosts if set
+    pub fn new(profile: &str) -> Self {
+        let path = std::env::var_os("KLEINWUERFEL_HOSTS_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_HOSTS_FILE));
+
+        HostsFile::at(path, profile)
+    }
+
+    fn at(path: PathBuf, profile: &str) -> Self {
+        HostsFile {
+            path,
+            profile: profile.to_string(),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    fn begin_marker(&self) -> String {
+        format!("# BEGIN kleinwuerfel {}", &self.profile)
+    }
+
+    fn end_marker(&self) -> String {
+        format!("# END kleinwuerfel {}", &self.profile)
+    }
+
+    /// The managed block mapping all hosts to the ip, as it's written to the hosts file
+    pub fn block(&self, ip: &str, hosts: &[String]) -> String {
+        self.block_lines(ip, hosts).join("\n")
+    }
+
+    fn block_lines(&self, ip: &str, hosts: &[String]) -> Vec<String> {
+        let mut lines = vec![self.begin_marker()];
+
+        lines.extend(hosts.iter().map(|host| format!("{} {}", ip, host)));
+        lines.push(self.end_marker());
+
+        lines
+    }
+
+    /// Replace the managed block in place, or append it if there is none yet
+    pub fn update(&self, ip: &str, hosts: &[String]) -> anyhow::Result<()> {
+        let mut content = self.read()?;
+        let block = self.block_lines(ip, hosts);
+
+        match self.block_range(&content.lines)? {
+            Some((begin, end)) => {
+                content.lines.splice(begin..=end, block);
+            }
+            None => {
+                // a blank line sets the block apart, unless there is one already
+                if content
+                    .lines
+                    .last()
+                    .map(|line| !line.trim().is_empty())
+                    .unwrap_or(false)
+                {
+                    content.lines.push(String::new());
+                }
+
+                content.lines.extend(block);
+                content.trailing_newline = true;
+            }
+        }
+
+        self.write(&content)
+    }
+
+    /// Remove the managed block. Returns false if there was none.
+    pub fn remove(&self) -> anyhow::Result<bool> {
+        if !self.path.exists() {
+            return Ok(false);
+        }
+
+        let mut content = self.read()?;
+
+        match self.block_range(&content.lines)? {
+            Some((begin, end)) => {
+                let last = end + 1 == content.lines.len();
+
+                content.lines.drain(begin..=end);
+
+                // the line in front of the block ended with a newline
+                if last {
+                    content.trailing_newline = true;
+                }
+
+                self.write(&content)?;
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// First and last line of the managed block. A block without end marker is an error, as guessing where it
+    /// ends could delete entries of the user.
+    fn block_range(&self, lines: &[String]) -> anyhow::Result<Option<(usize, usize)>> {
+        let begin_marker = self.begin_marker();
+        let end_marker = self.end_marker();
+
+        let begin = match lines.iter().position(|line| line.trim() == begin_marker) {
+            Some(begin) => begin,
+            None => return Ok(None),
+        };
+
+        match lines[begin..]
+            .iter()
+            .position(|line| line.trim() == end_marker)
+        {
+            Some(end) => Ok(Some((begin, begin + end))),
+            None => Err(anyhow::anyhow!(
+                "The hosts file '{}' contains '{}' in line {}, but no '{}'. Please fix the file by hand.",
+                self.path.display(),
+                begin_marker,
+                begin + 1,
+                end_marker
+            )),
+        }
+    }
+
+    fn read(&self) -> anyhow::Result<Content> {
+        let content = if self.path.exists() {
+            fs::read_to_string(&self.path)?
+        } else {
+            String::new()
+        };
+
+        Ok(Content {
+            lines: content.lines().map(|line| line.to_string()).collect(),
+            trailing_newline: content.ends_with('\n'),
+        })
+    }
+
+    fn write(&self, content: &Content) -> anyhow::Result<()> {
+        let mut text = content.lines.join("\n");
+
+        if content.trailing_newline && !text.is_empty() {
+            text.push('\n');
+        }
+
+        self.replace(&text).map_err(|err| {
+            anyhow::anyhow!(
+                "Cannot write the hosts file '{}': {}",
+                self.path.display(),
+                err
+            )
+        })
+    }
+
+    /// Write a temporary file next to the hosts file and rename it, so a failure while writing can't leave
+    /// the system with a truncated hosts file
+    fn replace(&self, text: &str) -> std::io::Result<()> {
+        let file_name = self
+            .path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let temporary = self
+            .path
+            .with_file_name(format!(".{}.kleinwuerfel", file_name));
+
+        fs::write(&temporary, text)?;
+
+        if let Ok(metadata) = fs::metadata(&self.path) {
+            fs::set_permissions(&temporary, metadata.permissions())?;
+        }
+
+        if let Err(err) = fs::rename(&temporary, &self.path) {
+            fs::remove_file(&temporary)?;
+
+            // /etc/hosts is a bind mount in containers, which can only be written in place
+            if self.path.exists() {
+                return fs::write(&self.path, text);
+            }
+
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// The hosts file lives as long as the returned directory
+    fn hosts_file(content: Option<&str>) -> (TempDir, HostsFile) {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("hosts");
+
+        if let Some(content) = content {
+            fs::write(&path, content).unwrap();
+        }
+
+        (directory, HostsFile::at(path, "demo"))
+    }
+
+    fn content(hosts_file: &HostsFile) -> String {
+        fs::read_to_string(hosts_file.path()).unwrap()
+    }
+
+    #[test]
+    fn update_creates_the_file() {
+        let (_directory, hosts_file) = hosts_file(None);
+
+        hosts_file
+            .update("192.168.49.2", &["a.local".to_string()])
+            .unwrap();
+
+        assert_eq!(
+            content(&hosts_file),
+            "# BEGIN kleinwuerfel demo\n192.168.49.2 a.local\n# END kleinwuerfel demo\n"
+        );
+    }
+
+    #[test]
+    fn update_appends_the_block_after_a_blank_line() {
+        let (_directory, hosts_file) = hosts_file(Some("127.0.0.1 localhost\n"));
+
+        hosts_file
+            .update(
+                "192.168.49.2",
+                &["a.local".to_string(), "b.local".to_string()],
+            )
+            .unwrap();
+
+        assert_eq!(
+            content(&hosts_file),
+            "127.0.0.1 localhost\n\n# BEGIN kleinwuerfel demo\n192.168.49.2 a.local\n192.168.49.2 b.local\n# END kleinwuerfel demo\n"
+        );
+    }
+
+    #[test]
+    fn update_replaces_the_block_in_place() {
+        let (_directory, hosts_file) = hosts_file(Some(
+            "127.0.0.1 localhost\n# BEGIN kleinwuerfel demo\n10.0.0.1 old.local\n# END kleinwuerfel demo\n::1 localhost\n\n\n",
+        ));
+
+        hosts_file
+            .update("192.168.49.2", &["a.local".to_string()])
+            .unwrap();
+
+        assert_eq!(
+            content(&hosts_file),
+            "127.0.0.1 localhost\n# BEGIN kleinwuerfel demo\n192.168.49.2 a.local\n# END kleinwuerfel demo\n::1 localhost\n\n\n"
+        );
+    }
+
+    #[test]
+    fn update_leaves_blocks_of_other_profiles_alone() {
+        let other = "# BEGIN kleinwuerfel other\n10.0.0.1 other.local\n# END kleinwuerfel other\n";
+        let (_directory, hosts_file) = hosts_file(Some(other));
+
+        hosts_file
+            .update("192.168.49.2", &["a.local".to_string()])
+            .unwrap();
+        assert!(content(&hosts_file).starts_with(other));
+
+        assert!(hosts_file.remove().unwrap());
+        assert_eq!(content(&hosts_file), format!("{}\n", other));
+    }
+
+    #[test]
+    fn remove_keeps_the_surrounding_lines() {
+        let (_directory, hosts_file) = hosts_file(Some(
+            "127.0.0.1 localhost\n\n# BEGIN kleinwuerfel demo\n10.0.0.1 a.local\n# END kleinwuerfel demo\n::1 localhost",
+        ));
+
+        assert!(hosts_file.remove().unwrap());
+        assert_eq!(content(&hosts_file), "127.0.0.1 localhost\n\n::1 localhost");
+    }
+
+    #[test]
+    fn remove_without_block() {
+        let (_directory, hosts_file) = hosts_file(Some("127.0.0.1 localhost\n\n"));
+
+        assert!(!hosts_file.remove().unwrap());
+        assert_eq!(content(&hosts_file), "127.0.0.1 localhost\n\n");
+    }
+
+    #[test]
+    fn remove_without_file() {
+        let (_directory, hosts_file) = hosts_file(None);
+
+        assert!(!hosts_file.remove().unwrap());
+        assert!(!hosts_file.path().exists());
+    }
+
+    #[test]
+    fn update_and_remove_dont_pile_up_blank_lines() {
+        let (_directory, hosts_file) = hosts_file(Some("127.0.0.1 localhost\n"));
+
+        for _ in 0..2 {
+            hosts_file
+                .update("192.168.49.2", &["a.local".to_string()])
+                .unwrap();
+            hosts_file.remove().unwrap();
+        }
+
+        assert_eq!(content(&hosts_file), "127.0.0.1 localhost\n\n");
+    }
+
+    #[test]
+    fn block_without_end_marker() {
+        let original =
+            "127.0.0.1 localhost\n# BEGIN kleinwuerfel demo\n10.0.0.1 a.local\n::1 localhost\n";
+        let (_directory, hosts_file) = hosts_file(Some(original));
+
+        let err = hosts_file
+            .update("192.168.49.2", &["a.local".to_string()])
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("no '# END kleinwuerfel demo'"),
+            "{}",
+            err
+        );
+
+        assert!(hosts_file.remove().is_err());
+        assert_eq!(content(&hosts_file), original);
+    }
+}
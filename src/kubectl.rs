@@ -1,156 +1,627 @@
 use std::{
-    path::PathBuf,
+    collections::BTreeMap,
+    net::TcpListener,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::{Arc, Mutex},
     thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::Ok;
 use colored::Colorize;
-use crossbeam_channel::{bounded, Receiver};
+use crossbeam_channel::{never, select, unbounded, Receiver, RecvTimeoutError, Sender};
 
-use crate::model::{Configuration, Helmchart};
+use crate::{
+    forward::{
+        all_forwards, local_ports, print_forwards, write_forwards, Forward, OnForwarded,
+        PortForward, SupervisedPortForward, SUPERVISE_INTERVAL,
+    },
+    health::{self, print_results, HealthCheckResult},
+    kubernetes::Kubernetes,
+    model::{Configuration, Helmchart, Port, PortTarget},
+    shutdown::{self, SpawnTracked},
+};
+
+/// How often a job of a hook is checked for having completed
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+const JOB_HOOKS: &str = "for 'job' hooks";
 
 #[derive(Debug, Clone)]
 pub struct Kubectl {
     configuration: Configuration,
-    kubectl_binary_path: PathBuf,
+    /// Only needed for 'kubectl port-forward', 'exec' and 'job', the rest goes through the Kubernetes API
+    kubectl_binary_path: Option<PathBuf>,
+    kube_context: String,
+    /// Connected on first use, as the cluster might not run before
+    kubernetes: Arc<Mutex<Option<Arc<Kubernetes>>>>,
 }
 
 impl Kubectl {
-    pub fn new(configuration: &Configuration, kubectl_binary_path: &PathBuf) -> Self {
+    pub fn new(
+        configuration: &Configuration,
+        kubectl_binary_path: Option<&PathBuf>,
+        kube_context: &str,
+    ) -> Self {
         Kubectl {
             configuration: configuration.to_owned(),
-            kubectl_binary_path: kubectl_binary_path.to_owned(),
+            kubectl_binary_path: kubectl_binary_path.cloned(),
+            kube_context: kube_context.to_string(),
+            kubernetes: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub fn port_forward_all_helmcharts(&self) -> anyhow::Result<()> {
-        if let Some(helmcharts) = &self.configuration.helmchart {
-            println!();
-            println!("{}", "Forwarding ports".bold().underline());
-            println!("Press Ctrl+C to stop the port forwarding.");
-            println!();
+    /// Same kubectl for a changed config file, sharing the connection to the Kubernetes API. Dropping it
+    /// would stop the native port forwards of the releases that didn't change.
+    pub fn with_configuration(&self, configuration: &Configuration) -> Self {
+        Kubectl {
+            configuration: configuration.to_owned(),
+            ..self.clone()
+        }
+    }
 
-            self.port_forwarding(helmcharts)?;
+    /// Client for the Kubernetes API, used to resolve port forward targets and for native port forwarding.
+    /// Connecting is retried on the next call if it fails, e.g. because the cluster didn't run yet.
+    fn kubernetes(&self) -> anyhow::Result<Arc<Kubernetes>> {
+        let mut kubernetes = self
+            .kubernetes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(kubernetes) = kubernetes.as_ref() {
+            return Ok(kubernetes.clone());
         }
 
-        Ok(())
+        let connected = Arc::new(Kubernetes::new(&self.kube_context).map_err(|err| {
+            anyhow::anyhow!(
+                "Cannot connect to the Kubernetes API of context '{}': {}",
+                &self.kube_context,
+                err
+            )
+        })?);
+
+        *kubernetes = Some(connected.clone());
+
+        Ok(connected)
     }
 
-    fn port_forwarding(&self, helmcharts: &[Helmchart]) -> anyhow::Result<()> {
-        let (sender, receiver) = bounded(0);
+    /// Namespace the release is installed in or, before the first install, the one 'helm upgrade' will
+    /// install it in: the `--namespace` of its `helm_args` or the namespace of the kube context
+    pub fn release_namespace(&self, release: &str) -> anyhow::Result<String> {
+        let kubernetes = self.kubernetes()?;
+
+        if let Some(namespace) = kubernetes.release_namespace(release)? {
+            return Ok(namespace);
+        }
+
+        let configured = self
+            .configuration
+            .helmchart
+            .iter()
+            .flatten()
+            .find(|helmchart| helmchart.name == release)
+            .and_then(|helmchart| helmchart.namespace());
+
+        Ok(configured.unwrap_or_else(|| kubernetes.default_namespace()))
+    }
 
-        let amount_of_receivers = helmcharts.len();
+    /// `required_for` completes the error if the kubectl binary is missing
+    fn command(&self, required_for: &str) -> anyhow::Result<Command> {
+        let kubectl_binary_path = self.kubectl_binary_path.as_ref().ok_or_else(|| {
+            anyhow::anyhow!(
+                "The binary 'kubectl' is missing in your $PATH. It's required {}. Installation guide: https://kubernetes.io/docs/tasks/tools/",
+                required_for
+            )
+        })?;
 
-        ctrlc::set_handler(move || {
-            for _ in 0..amount_of_receivers {
-                match sender.send(()) {
-                    std::result::Result::Ok(_) => {}
-                    Err(err) => println!("{}", err),
+        let mut command = Command::new(kubectl_binary_path);
+        command.arg("--context").arg(&self.kube_context);
+        Ok(command)
+    }
+
+    pub fn port_forward_all_helmcharts(
+        &self,
+        forwards_files: &[PathBuf],
+        health_checks: bool,
+        on_forwarded: Option<OnForwarded>,
+    ) -> anyhow::Result<()> {
+        match &self.configuration.helmchart {
+            Some(helmcharts) => self.port_forward_helmcharts(
+                helmcharts,
+                forwards_files,
+                health_checks,
+                on_forwarded,
+            )?,
+            None => {
+                if let Some(on_forwarded) = on_forwarded {
+                    on_forwarded(&[]);
                 }
             }
+        }
 
-            std::process::exit(0);
-        })?;
+        Ok(())
+    }
+
+    /// Forward the ports until shutdown. With `health_checks`, the health checks of the helm charts run
+    /// once their ports are forwarded. `on_forwarded` is called once with the initially forwarded ports.
+    pub fn port_forward_helmcharts(
+        &self,
+        helmcharts: &[Helmchart],
+        forwards_files: &[PathBuf],
+        health_checks: bool,
+        on_forwarded: Option<OnForwarded>,
+    ) -> anyhow::Result<()> {
+        check_local_ports(helmcharts)?;
+
+        println!();
+        println!("{}", "Forwarding ports".bold().underline());
+        println!("Press Ctrl+C to stop the port forwarding.");
+        println!();
+
+        self.port_forwarding(helmcharts, forwards_files, health_checks, on_forwarded)
+    }
+
+    fn port_forwarding(
+        &self,
+        helmcharts: &[Helmchart],
+        forwards_files: &[PathBuf],
+        health_checks: bool,
+        on_forwarded: Option<OnForwarded>,
+    ) -> anyhow::Result<()> {
+        // on shutdown the threads stop their port forwards and exit, which ends the loop below
+        let receiver = shutdown::subscribe();
+        let (forwards_sender, forwards_receiver) = unbounded();
+        let (results_sender, mut results_receiver) = unbounded();
+
+        let checked = helmcharts
+            .iter()
+            .filter(|helmchart| health_checks && !helmchart.health_check.is_empty())
+            .map(|helmchart| helmchart.name.to_string())
+            .collect::<Vec<String>>();
 
         let shared_self = Arc::new(Mutex::new(self.clone()));
 
         for helmchart in helmcharts {
             let receiver = receiver.clone();
+            let forwards_sender = forwards_sender.clone();
+            let results_sender = checked
+                .contains(&helmchart.name)
+                .then(|| results_sender.clone());
 
             match shared_self.lock() {
                 std::result::Result::Ok(shared_self) => {
                     let self_clone = shared_self.clone();
                     let helmchart = helmchart.clone();
 
-                    thread::spawn(
-                        move || match self_clone.port_forward(&helmchart, &receiver) {
+                    thread::spawn(move || {
+                        match self_clone.port_forward(
+                            &helmchart,
+                            &receiver,
+                            &forwards_sender,
+                            results_sender.as_ref(),
+                        ) {
                             std::result::Result::Ok(_) => {}
-                            Err(err) => println!("{}", err),
-                        },
-                    );
+                            Err(err) => {
+                                let _ = forwards_sender.send((helmchart.name.to_string(), vec![]));
+
+                                if let Some(results_sender) = results_sender {
+                                    let _ = results_sender.send((
+                                        helmchart.name.to_string(),
+                                        health::not_run(&helmchart, "port forwarding failed"),
+                                    ));
+                                }
+
+                                println!("{}", err)
+                            }
+                        }
+                    });
                 }
                 Err(err) => println!("{}", err),
             }
         }
 
-        receiver.recv()?;
+        drop(receiver);
+        drop(forwards_sender);
+        drop(results_sender);
+
+        // every thread reports its forwards once initially and again after every reconnect
+        let mut forwards = BTreeMap::new();
+
+        for _ in helmcharts {
+            let (release, release_forwards) = forwards_receiver.recv()?;
+
+            forwards.insert(release, release_forwards);
+        }
+
+        report_forwards(&forwards, forwards_files)?;
+
+        if let Some(on_forwarded) = on_forwarded {
+            on_forwarded(
+                &forwards
+                    .values()
+                    .flatten()
+                    .cloned()
+                    .collect::<Vec<Forward>>(),
+            );
+        }
+
+        // the results are shown in one table once all helm charts are checked
+        let mut results = BTreeMap::new();
+
+        loop {
+            select! {
+                recv(forwards_receiver) -> message => match message {
+                    std::result::Result::Ok((release, release_forwards)) => {
+                        forwards.insert(release, release_forwards);
+
+                        report_forwards(&forwards, forwards_files)?;
+                    }
+                    Err(_) => break,
+                },
+                recv(results_receiver) -> message => match message {
+                    std::result::Result::Ok((release, release_results)) => {
+                        results.insert(release, release_results);
+
+                        if results.len() == checked.len() {
+                            print_results(
+                                &checked
+                                    .iter()
+                                    .flat_map(|release| results[release].to_vec())
+                                    .collect::<Vec<HealthCheckResult>>(),
+                            );
+                        }
+                    }
+                    Err(_) => results_receiver = never(),
+                },
+            }
+        }
 
         Ok(())
     }
 
-    fn resolve_namespace(&self, helmchart: &Helmchart) -> anyhow::Result<Option<String>> {
-        let service_output = Command::new(&self.kubectl_binary_path)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .arg("get")
-            .arg("services")
-            .arg("--all-namespaces")
-            .arg("-o")
-            .arg(format!("jsonpath={{.items[?(@.metadata.annotations.meta\\.helm\\.sh/release-name==\"{}\")].metadata.namespace}}", &helmchart.name))
-            .spawn()?
-            .wait_with_output()?;
-
-        if let Some(namespace) = String::from_utf8(service_output.stdout)?
-            .split_whitespace()
-            .collect::<Vec<&str>>()
-            .first()
+    pub fn delete_persistent_volume_claims(&self, helmchart: &Helmchart) -> anyhow::Result<()> {
+        for claim in self
+            .kubernetes()?
+            .delete_persistent_volume_claims(&helmchart.name)?
         {
-            return Ok(Some(namespace.to_string()));
+            println!("persistentvolumeclaim \"{}\" deleted", claim);
         }
 
-        Ok(None)
+        Ok(())
     }
 
-    fn port_forward(&self, helmchart: &Helmchart, receiver: &Receiver<()>) -> anyhow::Result<()> {
-        if helmchart.ports.is_empty() {
-            return Ok(());
+    /// 'kubectl exec' of the command in the first service of the release, or in the first running pod
+    /// matching the selector
+    pub fn exec_command(
+        &self,
+        release: &str,
+        selector: Option<&str>,
+        container: Option<&str>,
+        command: &[String],
+    ) -> anyhow::Result<Command> {
+        let kubernetes = self.kubernetes()?;
+
+        let namespace = self.release_namespace(release)?;
+
+        let resource = match selector {
+            Some(selector) => kubernetes
+                .running_pod(&namespace, selector)?
+                .and_then(|pod| pod.metadata.name)
+                .map(|pod| format!("pod/{}", pod))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Cannot find a running pod for selector '{}'.", selector)
+                })?,
+            None => kubernetes
+                .release_service(release, &namespace)?
+                .and_then(|service| service.metadata.name)
+                .map(|service| format!("service/{}", service))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Cannot resolve the service of helm chart '{}'.", release)
+                })?,
+        };
+
+        let mut exec = self.command("for 'exec' hooks and health checks")?;
+        exec.arg("exec")
+            .arg("--namespace")
+            .arg(&namespace)
+            .arg(&resource);
+
+        if let Some(container) = container {
+            exec.arg("--container").arg(container);
         }
 
-        if let Some(namespace) = self.resolve_namespace(helmchart)? {
-            // query service
-            let service_output = Command::new(&self.kubectl_binary_path)
+        exec.arg("--").args(command);
+
+        Ok(exec)
+    }
+
+    /// Create the jobs of the manifest in the namespace of the release and wait for them to complete.
+    /// Jobs of a previous run are deleted first, as jobs can't be updated.
+    pub fn run_job(&self, release: &str, manifest: &Path, timeout: Duration) -> anyhow::Result<()> {
+        let kubernetes = self.kubernetes()?;
+        let namespace = self.release_namespace(release)?;
+
+        self.command(JOB_HOOKS)?
+            .stdout(Stdio::null())
+            .arg("delete")
+            .arg("--namespace")
+            .arg(&namespace)
+            .arg("--ignore-not-found")
+            .arg("--wait")
+            .arg("--filename")
+            .arg(manifest)
+            .spawn_tracked()?
+            .wait()?;
+
+        let output = self
+            .command(JOB_HOOKS)?
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
+            .arg("create")
             .arg("--namespace")
             .arg(&namespace)
-            .arg("get")
-            .arg("service")
-            .arg("-o")
-            .arg(format!("jsonpath={{.items[?(@.metadata.annotations.meta\\.helm\\.sh/release-name==\"{}\")].metadata.name}}", &helmchart.name))
-            .spawn()?
+            .arg("--filename")
+            .arg(manifest)
+            .arg("--output")
+            .arg("name")
+            .spawn_tracked()?
             .wait_with_output()?;
 
-            if let Some(service) = String::from_utf8(service_output.stdout)?
-                .split_whitespace()
-                .collect::<Vec<&str>>()
-                .first()
-            {
-                let mut arguments = vec![];
-                arguments.push("port-forward".to_string());
-                arguments.push("--namespace".to_string());
-                arguments.push(namespace);
-                arguments.push(format!("service/{}", service));
-
-                for port in &helmchart.ports {
-                    arguments.push(format!(":{}", port));
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Cannot create the job of '{}'.",
+                manifest.display()
+            ));
+        }
+
+        // e.g. "job.batch/seed"
+        let jobs = String::from_utf8(output.stdout)?
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("job.batch/"))
+            .map(str::to_string)
+            .collect::<Vec<String>>();
+
+        if jobs.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The manifest '{}' contains no job.",
+                manifest.display()
+            ));
+        }
+
+        let deadline = Instant::now() + timeout;
+
+        for job in jobs {
+            println!("Wait for job '{}'...", job);
+
+            let succeeded = loop {
+                if let Some(succeeded) = kubernetes.job_status(&namespace, &job)? {
+                    break succeeded;
                 }
 
-                let mut child = Command::new(&self.kubectl_binary_path)
-                    .args(&arguments)
-                    .spawn()?;
+                if Instant::now() > deadline || shutdown::requested() {
+                    return Err(anyhow::anyhow!(
+                        "The job '{}' didn't complete within {}s.",
+                        job,
+                        timeout.as_secs()
+                    ));
+                }
 
-                receiver.recv()?;
+                thread::sleep(JOB_POLL_INTERVAL);
+            };
 
-                child.kill()?;
-            } else {
-                println!("Cannot resolve service. No port-forward possible...")
+            if !succeeded {
+                self.command(JOB_HOOKS)?
+                    .arg("logs")
+                    .arg("--namespace")
+                    .arg(&namespace)
+                    .arg("--tail")
+                    .arg("20")
+                    .arg(format!("job/{}", job))
+                    .spawn_tracked()?
+                    .wait()?;
+
+                return Err(anyhow::anyhow!("The job '{}' failed.", job));
             }
-        } else {
-            println!("Cannot resolve namespace. No port-forward possible...")
         }
 
         Ok(())
     }
+
+    fn port_forward(
+        &self,
+        helmchart: &Helmchart,
+        receiver: &Receiver<()>,
+        forwards_sender: &Sender<(String, Vec<Forward>)>,
+        results_sender: Option<&Sender<(String, Vec<HealthCheckResult>)>>,
+    ) -> anyhow::Result<()> {
+        let mut supervised = SupervisedPortForward::start_all(self, helmchart);
+
+        forwards_sender.send((helmchart.name.to_string(), all_forwards(&supervised)))?;
+
+        if let Some(results_sender) = results_sender {
+            health::spawn(self, helmchart, local_ports(&supervised), results_sender);
+        }
+
+        while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(SUPERVISE_INTERVAL) {
+            let mut reconnected = false;
+
+            for port_forward in supervised.iter_mut() {
+                reconnected |= port_forward.supervise(self);
+            }
+
+            if reconnected {
+                forwards_sender.send((helmchart.name.to_string(), all_forwards(&supervised)))?;
+            }
+        }
+
+        for port_forward in supervised.iter_mut() {
+            port_forward.stop();
+        }
+
+        Ok(())
+    }
+
+    pub fn spawn_port_forward(
+        &self,
+        helmchart: &Helmchart,
+        target: &PortTarget,
+        ports: &[Port],
+    ) -> anyhow::Result<Option<PortForward>> {
+        if ports.is_empty() {
+            return Ok(None);
+        }
+
+        let kubernetes = self.kubernetes()?;
+
+        if !self.configuration.port_forward.kubectl {
+            match kubernetes.port_forward(&helmchart.name, target, ports) {
+                std::result::Result::Ok(native) => {
+                    return Ok(native.map(|native| PortForward::native(&helmchart.name, native)))
+                }
+                Err(err) => println!(
+                    "{}",
+                    format!(
+                        "Native port forwarding failed ({}). Fall back to 'kubectl port-forward'...",
+                        err
+                    )
+                    .yellow()
+                ),
+            }
+        }
+
+        let namespace = match kubernetes.release_namespace(&helmchart.name)? {
+            Some(namespace) => namespace,
+            None => {
+                println!("Cannot resolve namespace. No port-forward possible...");
+
+                return Ok(None);
+            }
+        };
+
+        let resource = match target {
+            PortTarget::Release => kubernetes
+                .release_service(&helmchart.name, &namespace)?
+                .and_then(|service| service.metadata.name)
+                .map(|service| format!("service/{}", service)),
+            PortTarget::Service(service) => kubernetes
+                .service(&namespace, service)?
+                .map(|_| format!("service/{}", service)),
+            PortTarget::Selector(selector) => kubernetes
+                .running_pod(&namespace, selector)?
+                .and_then(|pod| pod.metadata.name)
+                .map(|pod| format!("pod/{}", pod)),
+        };
+
+        let resource = match resource {
+            Some(resource) => resource,
+            None => {
+                match target {
+                    PortTarget::Selector(selector) => println!(
+                        "Cannot find a running pod for selector '{}'. No port-forward possible...",
+                        selector
+                    ),
+                    _ => println!("Cannot resolve service. No port-forward possible..."),
+                }
+
+                kubernetes.print_warning_events(&helmchart.name, &namespace, target);
+
+                return Ok(None);
+            }
+        };
+
+        // 'kubectl port-forward' to a service picks one of its pods and fails without a ready one
+        if let Some(service) = resource.strip_prefix("service/") {
+            if kubernetes.ready_endpoints(&namespace, service)? == 0 {
+                println!(
+                    "The service '{}' has no ready endpoints. No port-forward possible...",
+                    service
+                );
+
+                kubernetes.print_warning_events(&helmchart.name, &namespace, target);
+
+                return Ok(None);
+            }
+        }
+
+        let mut arguments = vec![
+            "port-forward".to_string(),
+            "--namespace".to_string(),
+            namespace,
+            resource.to_string(),
+        ];
+
+        for port in ports {
+            match port.local {
+                Some(local) => arguments.push(format!("{}:{}", local, port.remote)),
+                None => arguments.push(format!(":{}", port.remote)),
+            }
+        }
+
+        let child = self
+            .command("to forward ports via 'kubectl port-forward'")?
+            .stdout(Stdio::piped())
+            .args(&arguments)
+            .spawn_tracked()?;
+
+        let name = resource
+            .strip_prefix("service/")
+            .unwrap_or(&resource)
+            .to_string();
+
+        Ok(Some(PortForward::kubectl(
+            &helmchart.name,
+            &name,
+            child,
+            ports.len(),
+        )))
+    }
+}
+
+fn report_forwards(
+    forwards: &BTreeMap<String, Vec<Forward>>,
+    forwards_files: &[PathBuf],
+) -> anyhow::Result<()> {
+    let forwards = forwards
+        .values()
+        .flatten()
+        .cloned()
+        .collect::<Vec<Forward>>();
+
+    print_forwards(&forwards);
+
+    for forwards_file in forwards_files {
+        write_forwards(forwards_file, &forwards)?;
+    }
+
+    Ok(())
+}
+
+/// Fail before forwarding anything if a fixed local port is configured twice or already in use
+pub fn check_local_ports(helmcharts: &[Helmchart]) -> anyhow::Result<()> {
+    let mut used: Vec<(u16, &str)> = vec![];
+
+    for helmchart in helmcharts {
+        for local in helmchart.ports.iter().filter_map(|port| port.local) {
+            if let Some((_, other)) = used.iter().find(|(port, _)| *port == local) {
+                return Err(anyhow::anyhow!(
+                    "The local port {} is configured for helm chart '{}' and '{}'. Please use different ports.",
+                    local,
+                    other,
+                    &helmchart.name
+                ));
+            }
+
+            if TcpListener::bind(("127.0.0.1", local)).is_err() {
+                return Err(anyhow::anyhow!(
+                    "The local port {} of helm chart '{}' is already in use. Please stop the process using it or choose a different port.",
+                    local,
+                    &helmchart.name
+                ));
+            }
+
+            used.push((local, &helmchart.name));
+        }
+    }
+
+    Ok(())
 }
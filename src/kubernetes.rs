@@ -0,0 +1,657 @@
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use colored::Colorize;
+use k8s_openapi::{
+    api::{
+        batch::v1::Job,
+        core::v1::{Event, PersistentVolumeClaim, Pod, Secret, Service},
+        discovery::v1::EndpointSlice,
+    },
+    apimachinery::pkg::util::intstr::IntOrString,
+};
+use kube::{
+    api::{DeleteParams, ListParams},
+    config::{KubeConfigOptions, Kubeconfig},
+    Api, Client, Config,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::model::{Port, PortTarget, RemotePort};
+
+/// How often the pod of a native port forward is checked for still running
+const POD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+const RELEASE_ANNOTATION: &str = "meta.helm.sh/release-name";
+
+/// Label helm charts conventionally put on everything belonging to the release
+const INSTANCE_LABEL: &str = "app.kubernetes.io/instance";
+
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+/// How many warning events are shown when a port forward target doesn't run
+const WARNING_EVENTS: usize = 5;
+
+/// Typed client for the Kubernetes API of the minikube cluster, using the kubeconfig minikube writes.
+/// Blocks on its own runtime, so callers don't need to be async.
+pub struct Kubernetes {
+    runtime: Runtime,
+    client: Client,
+}
+
+impl fmt::Debug for Kubernetes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Kubernetes").finish_non_exhaustive()
+    }
+}
+
+impl Kubernetes {
+    pub fn new(kube_context: &str) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+
+        let kubeconfig = Kubeconfig::read()?;
+        let options = KubeConfigOptions {
+            context: Some(kube_context.to_string()),
+            ..Default::default()
+        };
+
+        let client = runtime.block_on(async {
+            let config = Config::from_custom_kubeconfig(kubeconfig, &options).await?;
+
+            anyhow::Ok(Client::try_from(config)?)
+        })?;
+
+        Ok(Kubernetes { runtime, client })
+    }
+
+    /// Namespace the helm release is installed in, taken from the secret helm stores the release in.
+    /// Falls back to the namespace of the first service of the release for other storage drivers.
+    pub fn release_namespace(&self, release: &str) -> anyhow::Result<Option<String>> {
+        let secrets =
+            self.runtime
+                .block_on(Api::<Secret>::all(self.client.clone()).list(
+                    &ListParams::default().labels(&format!("owner=helm,name={}", release)),
+                ))?;
+
+        if let Some(namespace) = secrets
+            .items
+            .into_iter()
+            .find_map(|secret| secret.metadata.namespace)
+        {
+            return Ok(Some(namespace));
+        }
+
+        Ok(self
+            .release_services(release)?
+            .into_iter()
+            .find_map(|service| service.metadata.namespace))
+    }
+
+    /// Namespace of the kube context, which helm installs releases in by default
+    pub fn default_namespace(&self) -> String {
+        self.client.default_namespace().to_string()
+    }
+
+    /// Services of the helm release in all namespaces, recognized by the annotation helm sets
+    pub fn release_services(&self, release: &str) -> anyhow::Result<Vec<Service>> {
+        let services = self
+            .runtime
+            .block_on(Api::<Service>::all(self.client.clone()).list(&ListParams::default()))?;
+
+        Ok(services
+            .items
+            .into_iter()
+            .filter(|service| {
+                service
+                    .metadata
+                    .annotations
+                    .as_ref()
+                    .and_then(|annotations| annotations.get(RELEASE_ANNOTATION))
+                    .map(|name| name == release)
+                    .unwrap_or(false)
+            })
+            .collect())
+    }
+
+    /// First service of the helm release in the namespace
+    pub fn release_service(
+        &self,
+        release: &str,
+        namespace: &str,
+    ) -> anyhow::Result<Option<Service>> {
+        Ok(self
+            .release_services(release)?
+            .into_iter()
+            .find(|service| service.metadata.namespace.as_deref() == Some(namespace)))
+    }
+
+    pub fn service(&self, namespace: &str, name: &str) -> anyhow::Result<Option<Service>> {
+        Ok(self
+            .runtime
+            .block_on(Api::<Service>::namespaced(self.client.clone(), namespace).get_opt(name))?)
+    }
+
+    /// First running pod matching the label selector
+    pub fn running_pod(&self, namespace: &str, selector: &str) -> anyhow::Result<Option<Pod>> {
+        let pods = self.runtime.block_on(
+            Api::<Pod>::namespaced(self.client.clone(), namespace).list(
+                &ListParams::default()
+                    .labels(selector)
+                    .fields("status.phase=Running"),
+            ),
+        )?;
+
+        Ok(pods.items.into_iter().next())
+    }
+
+    /// Endpoints of the service which are ready to receive traffic, i.e. pods a port forward can use
+    pub fn ready_endpoints(&self, namespace: &str, service: &str) -> anyhow::Result<usize> {
+        let endpoint_slices = self.runtime.block_on(
+            Api::<EndpointSlice>::namespaced(self.client.clone(), namespace).list(
+                &ListParams::default().labels(&format!("{}={}", SERVICE_NAME_LABEL, service)),
+            ),
+        )?;
+
+        // an endpoint without a ready condition counts as ready
+        Ok(endpoint_slices
+            .items
+            .iter()
+            .flat_map(|endpoint_slice| &endpoint_slice.endpoints)
+            .filter(|endpoint| {
+                endpoint
+                    .conditions
+                    .as_ref()
+                    .and_then(|conditions| conditions.ready)
+                    .unwrap_or(true)
+            })
+            .count())
+    }
+
+    /// Latest warning events of the pods matching the label selector, oldest first
+    pub fn warning_events(&self, namespace: &str, selector: &str) -> anyhow::Result<Vec<String>> {
+        let (pods, events) = self.runtime.block_on(async {
+            let pods = Api::<Pod>::namespaced(self.client.clone(), namespace)
+                .list(&ListParams::default().labels(selector))
+                .await?;
+            let events = Api::<Event>::namespaced(self.client.clone(), namespace)
+                .list(&ListParams::default().fields("type=Warning"))
+                .await?;
+
+            anyhow::Ok((pods, events))
+        })?;
+
+        let pods = pods
+            .items
+            .iter()
+            .map(pod_name)
+            .collect::<BTreeSet<String>>();
+
+        let mut events = events
+            .items
+            .into_iter()
+            .filter(|event| {
+                event.involved_object.kind.as_deref() == Some("Pod")
+                    && event
+                        .involved_object
+                        .name
+                        .as_ref()
+                        .map(|name| pods.contains(name))
+                        .unwrap_or(false)
+            })
+            .collect::<Vec<Event>>();
+
+        events.sort_by_key(|event| {
+            event
+                .last_timestamp
+                .as_ref()
+                .map(|time| time.0)
+                .or_else(|| event.event_time.as_ref().map(|time| time.0))
+        });
+
+        Ok(events
+            .iter()
+            .skip(events.len().saturating_sub(WARNING_EVENTS))
+            .map(|event| {
+                format!(
+                    "{}: {}: {}",
+                    event.involved_object.name.as_deref().unwrap_or_default(),
+                    event.reason.as_deref().unwrap_or_default(),
+                    event.message.as_deref().unwrap_or_default().trim()
+                )
+            })
+            .collect())
+    }
+
+    /// Show why the pods of a port forward target don't run, e.g. failing image pulls or probes
+    pub fn print_warning_events(&self, release: &str, namespace: &str, target: &PortTarget) {
+        let selector = match target {
+            PortTarget::Selector(selector) => selector.to_string(),
+            _ => format!("{}={}", INSTANCE_LABEL, release),
+        };
+
+        // the events only explain, not being able to fetch them changes nothing
+        if let Ok(events) = self.warning_events(namespace, &selector) {
+            for event in events {
+                println!("{}", event.yellow());
+            }
+        }
+    }
+
+    /// `Some(true)` once the job succeeded, `Some(false)` once it failed and `None` while it runs
+    pub fn job_status(&self, namespace: &str, name: &str) -> anyhow::Result<Option<bool>> {
+        let job = self
+            .runtime
+            .block_on(Api::<Job>::namespaced(self.client.clone(), namespace).get(name))?;
+
+        let status = job.status.unwrap_or_default();
+
+        let failed = status
+            .conditions
+            .iter()
+            .flatten()
+            .any(|condition| condition.type_ == "Failed" && condition.status == "True");
+
+        if failed {
+            Ok(Some(false))
+        } else if status.succeeded.unwrap_or_default() > 0 {
+            Ok(Some(true))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Delete the persistent volume claims of the helm release in all namespaces.
+    /// Returns the names of the deleted claims.
+    pub fn delete_persistent_volume_claims(&self, release: &str) -> anyhow::Result<Vec<String>> {
+        self.runtime.block_on(async {
+            let claims = Api::<PersistentVolumeClaim>::all(self.client.clone())
+                .list(&ListParams::default().labels(&format!("{}={}", INSTANCE_LABEL, release)))
+                .await?;
+
+            let mut deleted = vec![];
+
+            for claim in claims.items {
+                let name = claim.metadata.name.unwrap_or_default();
+                let namespace = claim.metadata.namespace.unwrap_or_default();
+
+                Api::<PersistentVolumeClaim>::namespaced(self.client.clone(), &namespace)
+                    .delete(&name, &DeleteParams::default())
+                    .await?;
+
+                deleted.push(name);
+            }
+
+            Ok(deleted)
+        })
+    }
+
+    /// Forward the ports to the target like 'kubectl port-forward' does, without the kubectl binary.
+    /// Returns `None` if the target can't be resolved (yet).
+    pub fn port_forward(
+        self: &Arc<Self>,
+        release: &str,
+        target: &PortTarget,
+        ports: &[Port],
+    ) -> anyhow::Result<Option<NativePortForward>> {
+        let namespace = match self.release_namespace(release)? {
+            Some(namespace) => namespace,
+            None => {
+                println!("Cannot resolve namespace. No port-forward possible...");
+
+                return Ok(None);
+            }
+        };
+
+        let resolved = match target {
+            PortTarget::Release => match self.release_service(release, &namespace)? {
+                Some(service) => self.resolve_service(&namespace, service, ports)?,
+                None => None,
+            },
+            PortTarget::Service(name) => match self.service(&namespace, name)? {
+                Some(service) => self.resolve_service(&namespace, service, ports)?,
+                None => None,
+            },
+            PortTarget::Selector(selector) => self
+                .running_pod(&namespace, selector)?
+                .map(|pod| {
+                    let remote_ports = ports
+                        .iter()
+                        .map(|port| container_port(&pod, &port.remote))
+                        .collect::<anyhow::Result<Vec<u16>>>()?;
+
+                    anyhow::Ok(Resolved {
+                        name: format!("pod/{}", pod_name(&pod)),
+                        pod: pod_name(&pod),
+                        remote_ports,
+                    })
+                })
+                .transpose()?,
+        };
+
+        let resolved = match resolved {
+            Some(resolved) => resolved,
+            None => {
+                match target {
+                    PortTarget::Selector(selector) => println!(
+                        "Cannot find a running pod for selector '{}'. No port-forward possible...",
+                        selector
+                    ),
+                    _ => println!("Cannot resolve service. No port-forward possible..."),
+                }
+
+                self.print_warning_events(release, &namespace, target);
+
+                return Ok(None);
+            }
+        };
+
+        self.runtime
+            .block_on(NativePortForward::start(
+                self.clone(),
+                Api::<Pod>::namespaced(self.client.clone(), &namespace),
+                resolved,
+                ports,
+            ))
+            .map(Some)
+    }
+
+    /// Pick a running pod of the service and translate the service ports to its container ports
+    fn resolve_service(
+        &self,
+        namespace: &str,
+        service: Service,
+        ports: &[Port],
+    ) -> anyhow::Result<Option<Resolved>> {
+        let name = service.metadata.name.to_owned().unwrap_or_default();
+        let spec = service.spec.unwrap_or_default();
+
+        let selector = spec
+            .selector
+            .unwrap_or_default()
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>()
+            .join(",");
+
+        if selector.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The service '{}' has no selector, its pods can't be forwarded to.",
+                name
+            ));
+        }
+
+        let pod = match self.running_pod(namespace, &selector)? {
+            Some(pod) => pod,
+            None => return Ok(None),
+        };
+
+        let service_ports = spec.ports.unwrap_or_default();
+        let mut remote_ports = vec![];
+
+        for port in ports {
+            let service_port = service_ports
+                .iter()
+                .find(|service_port| match &port.remote {
+                    RemotePort::Number(number) => service_port.port == i32::from(*number),
+                    RemotePort::Name(remote) => service_port.name.as_ref() == Some(remote),
+                })
+                .ok_or_else(|| {
+                    anyhow::anyhow!("The service '{}' has no port '{}'.", name, port.remote)
+                })?;
+
+            let remote_port = match &service_port.target_port {
+                Some(IntOrString::Int(number)) => u16::try_from(*number)?,
+                Some(IntOrString::String(target)) => {
+                    container_port(&pod, &RemotePort::Name(target.to_string()))?
+                }
+                None => u16::try_from(service_port.port)?,
+            };
+
+            remote_ports.push(remote_port);
+        }
+
+        Ok(Some(Resolved {
+            name,
+            pod: pod_name(&pod),
+            remote_ports,
+        }))
+    }
+}
+
+struct Resolved {
+    /// Service or pod, as shown in the table of forwarded ports
+    name: String,
+    pod: String,
+    /// Container ports, in the order of the configured ports
+    remote_ports: Vec<u16>,
+}
+
+fn pod_name(pod: &Pod) -> String {
+    pod.metadata.name.to_owned().unwrap_or_default()
+}
+
+fn container_port(pod: &Pod, remote: &RemotePort) -> anyhow::Result<u16> {
+    match remote {
+        RemotePort::Number(number) => Ok(*number),
+        RemotePort::Name(name) => {
+            let port = pod
+                .spec
+                .iter()
+                .flat_map(|spec| &spec.containers)
+                .flat_map(|container| container.ports.iter().flatten())
+                .find(|port| port.name.as_ref() == Some(name))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("The pod '{}' has no port named '{}'.", pod_name(pod), name)
+                })?;
+
+            Ok(u16::try_from(port.container_port)?)
+        }
+    }
+}
+
+/// Port forward running inside this process: each local connection gets its own stream to the pod
+pub struct NativePortForward {
+    pub name: String,
+    /// Local port of each forward, in the order of the configured ports
+    pub local_ports: Vec<u16>,
+    exited: Arc<Mutex<Option<String>>>,
+    tasks: Vec<JoinHandle<()>>,
+    /// Keeps the runtime the tasks are spawned on alive, even if the client that started them is dropped
+    _kubernetes: Arc<Kubernetes>,
+}
+
+impl NativePortForward {
+    async fn start(
+        kubernetes: Arc<Kubernetes>,
+        pods: Api<Pod>,
+        resolved: Resolved,
+        ports: &[Port],
+    ) -> anyhow::Result<Self> {
+        let exited = Arc::new(Mutex::new(None));
+        let mut local_ports = vec![];
+        let mut tasks = vec![];
+
+        for (port, remote_port) in ports.iter().zip(&resolved.remote_ports) {
+            let listener = TcpListener::bind(("127.0.0.1", port.local.unwrap_or(0))).await?;
+            let local_port = listener.local_addr()?.port();
+
+            local_ports.push(local_port);
+
+            tasks.push(tokio::spawn(accept(
+                listener,
+                pods.clone(),
+                resolved.pod.to_string(),
+                *remote_port,
+                exited.clone(),
+            )));
+        }
+
+        tasks.push(tokio::spawn(watch_pod(
+            pods,
+            resolved.pod.to_string(),
+            exited.clone(),
+        )));
+
+        Ok(NativePortForward {
+            name: resolved.name,
+            local_ports,
+            exited,
+            tasks,
+            _kubernetes: kubernetes,
+        })
+    }
+
+    /// Why the port forward stopped working, e.g. because the pod is gone
+    pub fn exited(&self) -> Option<String> {
+        lock(&self.exited).clone()
+    }
+
+    pub fn stop(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for NativePortForward {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn exit(exited: &Mutex<Option<String>>, reason: String) {
+    lock(exited).get_or_insert(reason);
+}
+
+/// Bytes sent to and received from a pod port over all connections
+#[derive(Default)]
+struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+async fn accept(
+    listener: TcpListener,
+    pods: Api<Pod>,
+    pod: String,
+    remote_port: u16,
+    exited: Arc<Mutex<Option<String>>>,
+) {
+    let traffic = Arc::new(Traffic::default());
+
+    // dropping the set when this task is aborted closes all connections
+    let mut connections = JoinSet::new();
+
+    loop {
+        let (connection, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                exit(&exited, format!("accepting connections failed: {}", err));
+
+                return;
+            }
+        };
+
+        while connections.try_join_next().is_some() {}
+
+        println!(
+            "Connection from {} to {}:{} opened",
+            peer, &pod, remote_port
+        );
+
+        let pods = pods.clone();
+        let pod = pod.to_string();
+        let traffic = traffic.clone();
+
+        connections.spawn(async move {
+            match forward_connection(&pods, &pod, remote_port, connection).await {
+                Ok((sent, received)) => {
+                    let total_sent = traffic.sent.fetch_add(sent, Ordering::Relaxed) + sent;
+                    let total_received =
+                        traffic.received.fetch_add(received, Ordering::Relaxed) + received;
+
+                    println!(
+                        "Connection from {} to {}:{} closed: sent {} bytes, received {} bytes (total: sent {} bytes, received {} bytes)",
+                        peer, &pod, remote_port, sent, received, total_sent, total_received
+                    );
+                }
+                Err(err) => println!(
+                    "Connection from {} to {}:{} failed: {}",
+                    peer, &pod, remote_port, err
+                ),
+            }
+        });
+    }
+}
+
+async fn forward_connection(
+    pods: &Api<Pod>,
+    pod: &str,
+    remote_port: u16,
+    mut connection: TcpStream,
+) -> anyhow::Result<(u64, u64)> {
+    let mut forwarder = pods.portforward(pod, &[remote_port]).await?;
+    let mut upstream = forwarder
+        .take_stream(remote_port)
+        .ok_or_else(|| anyhow::anyhow!("no stream for port {}", remote_port))?;
+
+    let (sent, received) = tokio::io::copy_bidirectional(&mut connection, &mut upstream).await?;
+
+    drop(upstream);
+    forwarder.join().await?;
+
+    Ok((sent, received))
+}
+
+/// Mark the port forward as exited once the pod doesn't run anymore
+async fn watch_pod(pods: Api<Pod>, pod: String, exited: Arc<Mutex<Option<String>>>) {
+    loop {
+        tokio::time::sleep(POD_CHECK_INTERVAL).await;
+
+        let reason = match pods.get_opt(&pod).await {
+            Ok(Some(current)) => {
+                let phase = current
+                    .status
+                    .as_ref()
+                    .and_then(|status| status.phase.to_owned())
+                    .unwrap_or_default();
+
+                if current.metadata.deletion_timestamp.is_some() {
+                    Some(format!("pod '{}' is terminating", &pod))
+                } else if phase != "Running" {
+                    Some(format!("pod '{}' is {}", &pod, phase))
+                } else {
+                    None
+                }
+            }
+            Ok(None) => Some(format!("pod '{}' is gone", &pod)),
+            Err(err) => Some(format!("checking pod '{}' failed: {}", &pod, err)),
+        };
+
+        if let Some(reason) = reason {
+            exit(&exited, reason);
+
+            return;
+        }
+    }
+}
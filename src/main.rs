@@ -1,85 +1,238 @@
-use std::fs;
+use std::{fs, path::Path};
 
 use clap::Parser;
-use cli::{Options, SubCommand};
+use cli::{ForwardAction, Options, SubCommand};
 use colored::Colorize;
 use which::which;
 
-use crate::{model::Configuration, orchestrator::Orchestrator};
+use crate::{
+    kubectl::check_local_ports,
+    model::{Configuration, DownMode},
+    orchestrator::Orchestrator,
+    selection::{find_helmchart, find_helmcharts},
+    supervisor::Supervisor,
+    watch::Watch,
+};
 
 mod cli;
+mod docker;
+mod forward;
+mod health;
 mod helm;
+mod hooks;
+mod hosts;
 mod kubectl;
+mod kubernetes;
 mod minikube;
 mod model;
 mod orchestrator;
+mod profile;
+mod selection;
+mod shutdown;
+mod supervisor;
+mod watch;
 
 fn main() -> anyhow::Result<()> {
-    let options = Options::parse();
+    shutdown::install()?;
 
-    let content = fs::read_to_string(options.config).map_err(|_| {
-        anyhow::anyhow!(
-            "Can't read config file. Please provide a proper location, like ./kleinwuerfel.toml"
-        )
-    })?;
+    let result = run(Options::parse());
+
+    // errors of terminated child processes are a consequence of the shutdown
+    if shutdown::requested() {
+        std::process::exit(shutdown::exit_code());
+    }
 
+    result
+}
+
+fn run(options: Options) -> anyhow::Result<()> {
     let minikube_binary_path = which("minikube").map_err(|_| {
         anyhow::anyhow!("The binary 'minikube' is missing in your $PATH. Installation guide: https://minikube.sigs.k8s.io/docs/start/")
     })?;
 
+    // listing profiles doesn't depend on a project
+    if let SubCommand::Profiles = options.subcommand {
+        return profile::list(&minikube_binary_path);
+    }
+
+    let content = fs::read_to_string(&options.config).map_err(|_| {
+        anyhow::anyhow!(
+            "Can't read config file. Please provide a proper location, like ./kleinwuerfel.toml"
+        )
+    })?;
+
     let helm_binary_path = which("helm").map_err(|_| {
         anyhow::anyhow!("The binary 'helm' is missing in your $PATH. Installation guide: https://helm.sh/docs/intro/install/")
     })?;
 
-    let kubectl_binary_path = which("kubectl").map_err(|_| {
-        anyhow::anyhow!("The binary 'kubectl' is missing in your $PATH. Installation guide: https://kubernetes.io/docs/tasks/tools/")
-    })?;
+    // kubectl is optional, ports are forwarded over the Kubernetes API. It's only used by the 'kubectl port-forward'
+    // fallback and by 'exec' and 'job' hooks and health checks.
+    let kubectl_binary_path = which("kubectl").ok();
+
+    // docker is optional, it's only used to build images and to skip loading images which are already present in minikube
+    let docker_binary_path = which("docker").ok();
 
     let configuration = toml::from_str::<Configuration>(&content)?;
 
+    helm::validate(&configuration)?;
+    forward::validate(&configuration)?;
+    hooks::validate(&configuration)?;
+
+    let project_directory = profile::project_directory(&options.config)?;
+    let profile = profile::resolve_profile(&configuration, &project_directory);
+
     let orchestrator = Orchestrator::new(
         &configuration,
         &minikube_binary_path,
         &helm_binary_path,
-        &kubectl_binary_path,
+        kubectl_binary_path.as_ref(),
+        docker_binary_path.as_ref(),
+        &project_directory,
+        &profile,
     );
 
     match options.subcommand {
         SubCommand::Up(arguments) => {
             println!("{}", "Bootstrap minikube".bold().underline());
 
-            if let Ok(true) = orchestrator.is_running() {
-                println!("Minikube is already running! Skip start sequence...");
-            } else {
-                orchestrator.start()?;
-            }
+            profile::register(&profile, &project_directory)?;
+
+            orchestrator.run_stage_hooks("pre_up", &configuration.pre_up, &[])?;
+            orchestrator.bootstrap(arguments.recreate)?;
 
             println!();
 
-            let helm_chart_repos = &configuration.helm_chart_repo.unwrap_or_default();
+            let helm_chart_repos = &configuration.helm_chart_repo.to_owned().unwrap_or_default();
+            let selection = arguments.selection();
 
             if arguments.no_deploy {
                 println!("{}", "Deployment".bold().underline());
                 println!("Disabled via '--no-deploy'!");
                 println!();
             } else if let Some(helmcharts) = &configuration.helmchart {
-                for helm_chart_repo in helm_chart_repos {
-                    orchestrator.add_helm_chart_repo(helm_chart_repo)?;
+                let helmcharts = selection.select(helmcharts)?;
+
+                if !selection.is_empty() {
+                    println!("{}", "Selected helm charts".bold().underline());
+                    for helm_chart in &helmcharts {
+                        println!("{}", helm_chart.name);
+                    }
+                    println!();
                 }
 
-                for helm_chart in helmcharts {
-                    orchestrator.deploy(helm_chart, helm_chart_repos)?;
-                }
+                orchestrator.deploy_all(&helmcharts, helm_chart_repos)?;
             } else {
                 println!("No helmcharts to deploy.")
             }
 
             orchestrator.list_deployed_helmcharts()?;
-            orchestrator.port_forward_all_helmcharts()?;
+            orchestrator.update_hosts()?;
+
+            if arguments.watch {
+                Watch::new(
+                    orchestrator,
+                    &configuration,
+                    &selection,
+                    &options.config,
+                    &project_directory,
+                    arguments.forwards_file.as_deref(),
+                )?
+                .run()?;
+            } else {
+                orchestrator
+                    .port_forward_all_helmcharts(arguments.forwards_file.as_slice(), true)?;
+            }
         }
-        SubCommand::Down => {
-            orchestrator.cleanup()?;
+        SubCommand::Down(arguments) => {
+            let mode = arguments.mode().unwrap_or_else(|| {
+                configuration
+                    .minikube
+                    .as_ref()
+                    .map(|minikube| minikube.down)
+                    .unwrap_or_default()
+            });
+
+            if !arguments.no_hooks {
+                orchestrator.run_pre_down_hooks()?;
+            }
+
+            orchestrator.down(mode)?;
+
+            if mode == DownMode::Delete {
+                profile::unregister(&profile)?;
+            }
+        }
+        SubCommand::Redeploy(arguments) => {
+            let helmcharts = configuration.helmchart.unwrap_or_default();
+            let helmcharts = find_helmcharts(&helmcharts, &arguments.releases)?;
+            let helm_chart_repos = &configuration.helm_chart_repo.unwrap_or_default();
+
+            orchestrator.deploy_all(&helmcharts, helm_chart_repos)?;
+        }
+        SubCommand::Uninstall(arguments) => {
+            let helmcharts = configuration.helmchart.unwrap_or_default();
+
+            for helmchart in find_helmcharts(&helmcharts, &arguments.releases)? {
+                orchestrator.uninstall(&helmchart, arguments.delete_pvcs)?;
+            }
+        }
+        SubCommand::Rollback(arguments) => {
+            let helmcharts = configuration.helmchart.unwrap_or_default();
+            let helmchart = find_helmchart(&helmcharts, &arguments.release)?;
+
+            orchestrator.rollback(helmchart, arguments.to)?;
+
+            // forwarding in the foreground too would fail on the local ports of the background process
+            if !Supervisor::new(&profile)?.restart(&helmchart.name)? {
+                orchestrator.port_forward_helmcharts(&[helmchart.to_owned()], &[], true)?;
+            }
+        }
+        SubCommand::Forward(arguments) => {
+            let supervisor = Supervisor::new(&profile)?;
+
+            match arguments.action {
+                Some(ForwardAction::Status) => supervisor.status()?,
+                Some(ForwardAction::Stop) => supervisor.stop()?,
+                None => {
+                    let helmcharts = configuration.helmchart.unwrap_or_default();
+                    let helmcharts = if arguments.releases.is_empty() {
+                        helmcharts
+                    } else {
+                        find_helmcharts(&helmcharts, &arguments.releases)?
+                    };
+
+                    orchestrator.ensure_running()?;
+
+                    if arguments.detach {
+                        check_local_ports(&helmcharts)?;
+
+                        supervisor.detach(
+                            Path::new(&options.config),
+                            &arguments.releases,
+                            arguments.forwards_file.as_deref(),
+                        )?;
+                    } else {
+                        let mut forwards_files =
+                            arguments.forwards_file.into_iter().collect::<Vec<_>>();
+
+                        if arguments.supervisor {
+                            forwards_files.push(supervisor.forwards_file());
+                        }
+
+                        orchestrator.port_forward_helmcharts(
+                            &helmcharts,
+                            &forwards_files,
+                            false,
+                        )?;
+
+                        if arguments.supervisor {
+                            supervisor.finish()?;
+                        }
+                    }
+                }
+            }
         }
+        SubCommand::Profiles => unreachable!("handled before reading the config file"),
     }
 
     Ok(())
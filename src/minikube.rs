@@ -1,24 +1,154 @@
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
-use crate::model::Configuration;
+use std::collections::BTreeMap;
+
+use serde_derive::Deserialize;
+
+use crate::{model::Configuration, shutdown::SpawnTracked};
+
+const DEFAULT_CPUS: u8 = 4;
+const DEFAULT_MEMORY: u16 = 8192;
+
+#[derive(Debug, Deserialize, Default)]
+struct ProfileList {
+    #[serde(default)]
+    valid: Vec<Profile>,
+
+    #[serde(default)]
+    invalid: Vec<Profile>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Profile {
+    #[serde(rename = "Name")]
+    pub name: String,
+
+    #[serde(rename = "Status", default)]
+    pub status: String,
+
+    #[serde(rename = "Config")]
+    config: Option<ProfileConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ProfileConfig {
+    #[serde(rename = "CPUs")]
+    cpus: Option<u64>,
+
+    #[serde(rename = "Memory")]
+    memory: Option<u64>,
+
+    #[serde(rename = "Driver")]
+    driver: Option<String>,
+
+    #[serde(rename = "KubernetesConfig")]
+    kubernetes_config: Option<KubernetesConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct KubernetesConfig {
+    #[serde(rename = "KubernetesVersion")]
+    kubernetes_version: Option<String>,
+}
+
+/// Output of `minikube status --output json`, which is a list for multi node clusters
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StatusOutput {
+    Single(Status),
+    Multiple(Vec<Status>),
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Status {
+    #[serde(rename = "Host", default)]
+    pub host: String,
+
+    #[serde(rename = "Kubelet", default)]
+    pub kubelet: String,
+
+    #[serde(rename = "APIServer", default)]
+    pub apiserver: String,
+
+    #[serde(rename = "Kubeconfig", default)]
+    pub kubeconfig: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Paused,
+    Stopped,
+    Nonexistent,
+    /// The cluster runs, but kubeconfig points to a different address
+    Misconfigured,
+    /// The host runs, but kubelet or apiserver don't
+    Degraded,
+    Broken(String),
+}
+
+impl Status {
+    pub fn state(&self) -> State {
+        match (
+            self.host.as_str(),
+            self.kubelet.as_str(),
+            self.apiserver.as_str(),
+            self.kubeconfig.as_str(),
+        ) {
+            ("Running", "Running", "Running", "Configured") => State::Running,
+            ("Running", "Running", "Running", "Misconfigured") => State::Misconfigured,
+            ("Running", _, "Paused", _) => State::Paused,
+            ("Running", "Running" | "Stopped", "Running" | "Stopped", _) => State::Degraded,
+            ("Stopped", _, _, _) => State::Stopped,
+            ("Nonexistent" | "", _, _, _) => State::Nonexistent,
+            _ => State::Broken(format!(
+                "host: {}, kubelet: {}, apiserver: {}, kubeconfig: {}",
+                self.host, self.kubelet, self.apiserver, self.kubeconfig
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClusterImage {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Addon {
+    #[serde(rename = "Status")]
+    status: String,
+}
 
 pub struct Minikube {
     configuration: Configuration,
     minikube_binary_path: PathBuf,
+    profile: String,
 }
 
 impl Minikube {
-    pub fn new(configuration: &Configuration, minikube_binary_path: &PathBuf) -> Self {
+    pub fn new(
+        configuration: &Configuration,
+        minikube_binary_path: &PathBuf,
+        profile: &str,
+    ) -> Self {
         Minikube {
             configuration: configuration.clone(),
             minikube_binary_path: minikube_binary_path.to_owned(),
+            profile: profile.to_string(),
         }
     }
 
-    pub fn start(&self) -> anyhow::Result<()> {
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.minikube_binary_path);
+        command.arg("--profile").arg(&self.profile);
+        command
+    }
+
+    fn start_arguments(&self) -> anyhow::Result<Vec<String>> {
         let mut arguments: Vec<String> = vec![];
         arguments.push("start".to_string());
 
@@ -26,59 +156,395 @@ impl Minikube {
             let cpus = minikube
                 .cpus
                 .map(|cpu| cpu.to_string())
-                .unwrap_or_else(|| "4".to_string());
+                .unwrap_or_else(|| DEFAULT_CPUS.to_string());
             let memory = minikube
                 .memory
                 .map(|memory| memory.to_string())
-                .unwrap_or_else(|| "8192".to_string());
+                .unwrap_or_else(|| DEFAULT_MEMORY.to_string());
 
             arguments.push("--cpus".to_string());
             arguments.push(cpus);
             arguments.push("--memory".to_string());
             arguments.push(memory);
+
+            let options = [
+                ("--driver", &minikube.driver),
+                ("--kubernetes-version", &minikube.kubernetes_version),
+                ("--disk-size", &minikube.disk_size),
+                ("--container-runtime", &minikube.container_runtime),
+            ];
+
+            for (flag, value) in options {
+                if let Some(value) = value {
+                    arguments.push(flag.to_string());
+                    arguments.push(value.to_string());
+                }
+            }
+
+            if let Some(nodes) = minikube.nodes {
+                arguments.push("--nodes".to_string());
+                arguments.push(nodes.to_string());
+            }
+
+            for extra_config in &minikube.extra_config {
+                arguments.push("--extra-config".to_string());
+                arguments.push(extra_config.to_string());
+            }
+
+            if !minikube.feature_gates.is_empty() {
+                let feature_gates = minikube
+                    .feature_gates
+                    .iter()
+                    .map(|(feature_gate, enabled)| format!("{}={}", feature_gate, enabled))
+                    .collect::<Vec<String>>()
+                    .join(",");
+
+                arguments.push("--feature-gates".to_string());
+                arguments.push(feature_gates);
+            }
+
+            for insecure_registry in &minikube.insecure_registry {
+                arguments.push("--insecure-registry".to_string());
+                arguments.push(insecure_registry.to_string());
+            }
+
+            if let Some(mount) = &minikube.mount {
+                if !mount.contains(':') {
+                    return Err(anyhow::anyhow!(
+                        "Invalid mount '{}'. Please provide it as '<host directory>:<minikube directory>'.",
+                        mount
+                    ));
+                }
+
+                arguments.push("--mount".to_string());
+                arguments.push("--mount-string".to_string());
+                arguments.push(mount.to_string());
+            }
+
+            arguments.extend(minikube.start_args.to_owned());
         } else {
             arguments.push("--cpus".to_string());
-            arguments.push("4".to_string());
+            arguments.push(DEFAULT_CPUS.to_string());
             arguments.push("--memory".to_string());
-            arguments.push("8192".to_string());
+            arguments.push(DEFAULT_MEMORY.to_string());
         }
 
-        Command::new(&self.minikube_binary_path)
-            .args(&arguments)
-            .spawn()?
-            .wait()?;
+        Ok(arguments)
+    }
 
-        if let Some(minikube) = &self.configuration.minikube {
-            for addon in &minikube.addons {
-                Command::new(&self.minikube_binary_path)
-                    .arg("addons")
-                    .arg("enable")
-                    .arg(addon)
-                    .spawn()?
-                    .wait()?;
+    pub fn start(&self) -> anyhow::Result<()> {
+        let arguments = self.start_arguments()?;
+
+        self.command().args(&arguments).spawn_tracked()?.wait()?;
+
+        self.enable_addons(&self.addons())?;
+
+        Ok(())
+    }
+
+    /// Addons from the config file, including the ingress addon if `ingress` is set
+    fn addons(&self) -> Vec<String> {
+        match &self.configuration.minikube {
+            Some(minikube) => {
+                let mut addons = minikube.addons.to_owned();
+
+                if minikube.ingress && !addons.iter().any(|addon| addon == "ingress") {
+                    addons.push("ingress".to_string());
+                }
+
+                addons
             }
+            None => vec![],
+        }
+    }
+
+    pub fn enable_addons(&self, addons: &[String]) -> anyhow::Result<()> {
+        for addon in addons {
+            self.command()
+                .arg("addons")
+                .arg("enable")
+                .arg(addon)
+                .spawn_tracked()?
+                .wait()?;
         }
 
         Ok(())
     }
 
+    /// Addons from the config file which are not enabled in the running cluster
+    pub fn missing_addons(&self) -> anyhow::Result<Vec<String>> {
+        let addons = self.addons();
+
+        if addons.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let output = self
+            .command()
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .arg("addons")
+            .arg("list")
+            .arg("--output")
+            .arg("json")
+            .spawn_tracked()?
+            .wait_with_output()?;
+
+        let enabled = serde_json::from_slice::<BTreeMap<String, Addon>>(&output.stdout)?;
+
+        Ok(addons
+            .iter()
+            .filter(|addon| {
+                enabled
+                    .get(addon.as_str())
+                    .map(|enabled| enabled.status != "enabled")
+                    .unwrap_or(true)
+            })
+            .cloned()
+            .collect())
+    }
+
+    /// Differences between the running cluster and the config file, which require a new cluster
+    pub fn drift(&self) -> anyhow::Result<Vec<String>> {
+        let profile = Minikube::profiles(&self.minikube_binary_path)?
+            .into_iter()
+            .find(|profile| profile.name == self.profile);
+
+        let config = match profile.and_then(|profile| profile.config) {
+            Some(config) => config,
+            None => return Ok(vec![]),
+        };
+
+        let minikube = self.configuration.minikube.as_ref();
+        let mut drift = vec![];
+
+        let cpus = minikube
+            .and_then(|minikube| minikube.cpus)
+            .unwrap_or(DEFAULT_CPUS);
+
+        if let Some(running) = config.cpus.filter(|running| *running != cpus as u64) {
+            drift.push(format!("CPUs: running {}, configured {}", running, cpus));
+        }
+
+        let memory = minikube
+            .and_then(|minikube| minikube.memory)
+            .unwrap_or(DEFAULT_MEMORY);
+
+        if let Some(running) = config.memory.filter(|running| *running != memory as u64) {
+            drift.push(format!(
+                "Memory: running {} MB, configured {} MB",
+                running, memory
+            ));
+        }
+
+        if let (Some(driver), Some(running)) = (
+            minikube.and_then(|minikube| minikube.driver.as_ref()),
+            &config.driver,
+        ) {
+            if driver != running {
+                drift.push(format!(
+                    "Driver: running {}, configured {}",
+                    running, driver
+                ));
+            }
+        }
+
+        let running_kubernetes_version = config
+            .kubernetes_config
+            .and_then(|kubernetes_config| kubernetes_config.kubernetes_version);
+
+        if let (Some(kubernetes_version), Some(running)) = (
+            minikube.and_then(|minikube| minikube.kubernetes_version.as_ref()),
+            running_kubernetes_version,
+        ) {
+            if kubernetes_version.trim_start_matches('v') != running.trim_start_matches('v') {
+                drift.push(format!(
+                    "Kubernetes version: running {}, configured {}",
+                    running, kubernetes_version
+                ));
+            }
+        }
+
+        Ok(drift)
+    }
+
     pub fn cleanup(&self) -> anyhow::Result<()> {
-        Command::new(&self.minikube_binary_path)
-            .arg("delete")
-            .spawn()?
+        self.command().arg("delete").spawn_tracked()?.wait()?;
+
+        Ok(())
+    }
+
+    pub fn stop(&self) -> anyhow::Result<()> {
+        self.command().arg("stop").spawn_tracked()?.wait()?;
+
+        Ok(())
+    }
+
+    pub fn pause(&self) -> anyhow::Result<()> {
+        self.command().arg("pause").spawn_tracked()?.wait()?;
+
+        Ok(())
+    }
+
+    pub fn unpause(&self) -> anyhow::Result<()> {
+        self.command().arg("unpause").spawn_tracked()?.wait()?;
+
+        Ok(())
+    }
+
+    /// Ids of all images present in the cluster, without the 'sha256:' prefix
+    pub fn image_ids(&self) -> anyhow::Result<Vec<String>> {
+        let output = self
+            .command()
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .arg("image")
+            .arg("ls")
+            .arg("--format")
+            .arg("json")
+            .spawn_tracked()?
+            .wait_with_output()?;
+
+        let images = serde_json::from_slice::<Vec<ClusterImage>>(&output.stdout)?;
+
+        Ok(images
+            .into_iter()
+            .map(|image| image.id.trim_start_matches("sha256:").to_string())
+            .collect())
+    }
+
+    pub fn load_image(&self, image: &str) -> anyhow::Result<()> {
+        let status = self
+            .command()
+            .arg("image")
+            .arg("load")
+            .arg(image)
+            .spawn_tracked()?
+            .wait()?;
+
+        if !status.success() {
+            return Err(anyhow::anyhow!("Cannot load image '{}'.", image));
+        }
+
+        Ok(())
+    }
+
+    pub fn build_image(
+        &self,
+        image: &str,
+        context: &Path,
+        dockerfile: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let mut command = self.command();
+        command.arg("image").arg("build").arg("--tag").arg(image);
+
+        if let Some(dockerfile) = dockerfile {
+            command.arg("--file").arg(dockerfile);
+        }
+
+        let status = command.arg(context).spawn_tracked()?.wait()?;
+
+        if !status.success() {
+            return Err(anyhow::anyhow!("Cannot build image '{}'.", image));
+        }
+
+        Ok(())
+    }
+
+    /// Environment variables pointing docker to the docker daemon of minikube
+    pub fn docker_env(&self) -> anyhow::Result<BTreeMap<String, String>> {
+        let output = self
+            .command()
+            .stdout(Stdio::piped())
+            .arg("docker-env")
+            .arg("--shell")
+            .arg("none")
+            .spawn_tracked()?
+            .wait_with_output()?;
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Cannot read the docker environment of minikube. Building images requires the 'docker' container runtime."
+            ));
+        }
+
+        Ok(String::from_utf8(output.stdout)?
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect())
+    }
+
+    pub fn ip(&self) -> anyhow::Result<String> {
+        let output = self
+            .command()
+            .stdout(Stdio::piped())
+            .arg("ip")
+            .spawn_tracked()?
+            .wait_with_output()?;
+
+        let ip = String::from_utf8(output.stdout)?.trim().to_string();
+
+        if !output.status.success() || ip.is_empty() {
+            return Err(anyhow::anyhow!("Cannot determine the ip of minikube."));
+        }
+
+        Ok(ip)
+    }
+
+    pub fn update_context(&self) -> anyhow::Result<()> {
+        self.command()
+            .arg("update-context")
+            .spawn_tracked()?
             .wait()?;
 
         Ok(())
     }
 
-    pub fn is_running(&self) -> anyhow::Result<bool> {
-        let output = Command::new(&self.minikube_binary_path)
+    pub fn status(&self) -> anyhow::Result<Status> {
+        let output = self
+            .command()
             .arg("status")
-            .stdout(Stdio::null())
+            .arg("--output")
+            .arg("json")
+            .stdout(Stdio::piped())
             .stderr(Stdio::null())
-            .spawn()?
+            .spawn_tracked()?
             .wait_with_output()?;
 
-        Ok(output.status.success())
+        // 'minikube status' encodes the state in its exit code as well, so we only rely on the output.
+        // An unknown profile leads to an empty output.
+        let status = match serde_json::from_slice::<StatusOutput>(&output.stdout) {
+            Ok(StatusOutput::Single(status)) => status,
+            Ok(StatusOutput::Multiple(statuses)) => statuses.into_iter().next().unwrap_or_default(),
+            Err(_) => Status::default(),
+        };
+
+        Ok(status)
+    }
+
+    pub fn profiles(minikube_binary_path: &PathBuf) -> anyhow::Result<Vec<Profile>> {
+        let output = Command::new(minikube_binary_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .arg("profile")
+            .arg("list")
+            .arg("--output")
+            .arg("json")
+            .spawn_tracked()?
+            .wait_with_output()?;
+
+        // minikube exits with an error if there are no profiles at all
+        let profile_list = if output.status.success() {
+            serde_json::from_slice::<ProfileList>(&output.stdout)?
+        } else {
+            ProfileList::default()
+        };
+
+        Ok(profile_list
+            .valid
+            .into_iter()
+            .chain(profile_list.invalid)
+            .collect())
     }
 }
@@ -1,8 +1,9 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fmt};
 
-use serde_derive::Deserialize;
+use serde::de::{self, value::MapAccessDeserializer, Deserializer, MapAccess, Visitor};
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct HelmChartRepo {
     pub name: String,
     pub url: String,
@@ -10,9 +11,17 @@ pub struct HelmChartRepo {
     pub password: Option<String>,
 
     pub values: Option<String>,
+
+    /// Appended to 'helm repo add'
+    #[serde(default)]
+    pub helm_args: Vec<String>,
+
+    /// Appended to 'helm registry login', which accepts other flags than 'helm repo add'
+    #[serde(default)]
+    pub login_args: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Helmchart {
     pub helm_chart_repo: String,
     pub name: String,
@@ -21,24 +30,733 @@ pub struct Helmchart {
     pub values: BTreeMap<String, String>,
 
     #[serde(default)]
-    pub ports: Vec<u16>,
+    pub ports: Vec<Port>,
+
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+
+    #[serde(default)]
+    pub helm_args: Vec<String>,
+
+    #[serde(default)]
+    pub images: Vec<Image>,
+
+    pub build: Option<Build>,
+
+    /// Host names of the ingresses of the chart, mapped to the minikube ip in the hosts file
+    #[serde(default)]
+    pub hosts: Vec<String>,
+
+    /// Checks run once the chart is deployed and its ports are forwarded
+    #[serde(default)]
+    pub health_check: Vec<HealthCheck>,
+
+    /// Run right before and after 'helm upgrade'. The ports aren't forwarded yet, use 'post_up' for hooks
+    /// that need them.
+    #[serde(default)]
+    pub pre_deploy: Vec<Hook>,
+
+    #[serde(default)]
+    pub post_deploy: Vec<Hook>,
+
+    #[serde(flatten)]
+    pub helm: HelmOptions,
+}
+
+impl Helmchart {
+    /// Namespace passed to 'helm upgrade' via `helm_args`, if any
+    pub fn namespace(&self) -> Option<String> {
+        self.helm_arg_values("--namespace", "-n").into_iter().next()
+    }
+
+    /// Values files passed to 'helm upgrade' via `helm_args`, relative to the working directory like helm
+    /// reads them
+    pub fn values_files(&self) -> Vec<String> {
+        self.helm_arg_values("--values", "-f")
+            .iter()
+            // helm also accepts a comma separated list
+            .flat_map(|values_files| values_files.split(','))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Values of a flag of `helm_args`, given as `--flag value`, `--flag=value` or `-f value`
+    fn helm_arg_values(&self, flag: &str, short_flag: &str) -> Vec<String> {
+        let mut values = vec![];
+        let mut helm_args = self.helm_args.iter();
+
+        while let Some(helm_arg) = helm_args.next() {
+            if let Some(value) = helm_arg
+                .strip_prefix(flag)
+                .and_then(|value| value.strip_prefix('='))
+            {
+                values.push(value.to_string());
+            } else if helm_arg == flag || helm_arg == short_flag {
+                values.extend(helm_args.next().cloned());
+            }
+        }
+
+        values
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct HelmOptions {
+    pub timeout: Option<String>,
+    pub atomic: Option<bool>,
+    pub wait: Option<bool>,
+    pub wait_for_jobs: Option<bool>,
+    pub cleanup_on_fail: Option<bool>,
+}
+
+impl HelmOptions {
+    /// Options set in `other` take precedence over the ones set in `self`
+    pub fn merge(&self, other: &HelmOptions) -> HelmOptions {
+        HelmOptions {
+            timeout: other.timeout.to_owned().or_else(|| self.timeout.to_owned()),
+            atomic: other.atomic.or(self.atomic),
+            wait: other.wait.or(self.wait),
+            wait_for_jobs: other.wait_for_jobs.or(self.wait_for_jobs),
+            cleanup_on_fail: other.cleanup_on_fail.or(self.cleanup_on_fail),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct PortForwardOptions {
+    /// Use 'kubectl port-forward' instead of forwarding over the Kubernetes API
+    #[serde(default)]
+    pub kubectl: bool,
+}
+
+/// Port to forward, kubectl picks a random local port if `local` is not set
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "PortEntry")]
+pub struct Port {
+    pub local: Option<u16>,
+    pub remote: RemotePort,
+    pub target: PortTarget,
+}
+
+/// Port number or name of the port of the service or pod
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum RemotePort {
+    Number(u16),
+    Name(String),
+}
+
+impl fmt::Display for RemotePort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemotePort::Number(number) => write!(f, "{}", number),
+            RemotePort::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Ports with the same target are forwarded by the same kubectl process
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PortTarget {
+    /// The first service of the helm release
+    Release,
+    Service(String),
+    /// The first running pod matching the label selector
+    Selector(String),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum PortEntry {
+    Remote(RemotePort),
+    Port {
+        local: Option<u16>,
+        remote: RemotePort,
+        service: Option<String>,
+        selector: Option<String>,
+    },
+}
+
+impl TryFrom<PortEntry> for Port {
+    type Error = String;
+
+    fn try_from(port_entry: PortEntry) -> Result<Self, Self::Error> {
+        match port_entry {
+            PortEntry::Remote(remote) => Ok(Port {
+                local: None,
+                remote,
+                target: PortTarget::Release,
+            }),
+            PortEntry::Port {
+                local,
+                remote,
+                service,
+                selector,
+            } => {
+                let target = match (service, selector) {
+                    (None, None) => PortTarget::Release,
+                    (Some(service), None) => PortTarget::Service(service),
+                    (None, Some(selector)) => PortTarget::Selector(selector),
+                    (Some(_), Some(_)) => {
+                        return Err(format!(
+                            "port {} has both a 'service' and a 'selector', please use only one of them",
+                            remote
+                        ))
+                    }
+                };
+
+                Ok(Port {
+                    local,
+                    remote,
+                    target,
+                })
+            }
+        }
+    }
+}
+
+/// Check of a deployed helm chart, retried until it passes or all retries are used up
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "HealthCheckEntry")]
+pub struct HealthCheck {
+    pub probe: Probe,
+    /// Seconds a single attempt may take
+    pub timeout: u64,
+    /// Attempts after the first one failed
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    /// GET request to the path over the local port forwarded to `port`
+    Http {
+        path: String,
+        port: RemotePort,
+        status: u16,
+    },
+    /// Connection over the local port forwarded to `port`, which must not be closed right away
+    Tcp { port: RemotePort },
+    /// Command run via 'kubectl exec' in the first service of the release or a pod matching `selector`
+    Exec {
+        command: Vec<String>,
+        selector: Option<String>,
+        container: Option<String>,
+    },
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Probe::Http { path, port, .. } => write!(f, "GET {} ({})", path, port),
+            Probe::Tcp { port } => write!(f, "TCP {}", port),
+            Probe::Exec { command, .. } => write!(f, "exec {}", command.join(" ")),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckEntry {
+    http: Option<String>,
+    tcp: Option<RemotePort>,
+    exec: Option<Vec<String>>,
+    port: Option<RemotePort>,
+    status: Option<u16>,
+    selector: Option<String>,
+    container: Option<String>,
+    timeout: Option<u64>,
+    retries: Option<u32>,
+}
+
+impl TryFrom<HealthCheckEntry> for HealthCheck {
+    type Error = String;
+
+    fn try_from(entry: HealthCheckEntry) -> Result<Self, Self::Error> {
+        let probe = match (entry.http, entry.tcp, entry.exec) {
+            (Some(path), None, None) => Probe::Http {
+                port: entry
+                    .port
+                    .ok_or_else(|| format!("health check 'http = \"{}\"' needs a 'port'", path))?,
+                path,
+                status: entry.status.unwrap_or(200),
+            },
+            (None, Some(port), None) => Probe::Tcp { port },
+            (None, None, Some(command)) if !command.is_empty() => Probe::Exec {
+                command,
+                selector: entry.selector,
+                container: entry.container,
+            },
+            _ => return Err(
+                "a health check needs exactly one of 'http', 'tcp' or 'exec' (a non-empty command)"
+                    .to_string(),
+            ),
+        };
+
+        Ok(HealthCheck {
+            probe,
+            timeout: entry.timeout.unwrap_or(5),
+            retries: entry.retries.unwrap_or(10),
+        })
+    }
+}
+
+/// Step run before or after a lifecycle stage, e.g. to migrate a database before deploying
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "HookEntry")]
+pub struct Hook {
+    pub action: HookAction,
+    /// Release whose namespace the hook runs in, defaults to the helm chart of the hook
+    pub release: Option<String>,
+    /// Passed to local commands and 'kubectl exec' in addition to the variables kleinwuerfel sets, not allowed for jobs
+    pub env: BTreeMap<String, String>,
+    /// Seconds to wait for a job to complete
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookAction {
+    /// Program and arguments run in the project directory, a plain string is run via 'sh -c'
+    Local(Vec<String>),
+    /// Command run via 'kubectl exec' in the first service of the release or a pod matching `selector`
+    Exec {
+        command: Vec<String>,
+        selector: Option<String>,
+        container: Option<String>,
+    },
+    /// Job manifest relative to the config file, created in the namespace of the release
+    Job(String),
+}
+
+impl HookAction {
+    /// 'kubectl exec' and jobs need a release to find the namespace
+    pub fn in_cluster(&self) -> bool {
+        !matches!(self, HookAction::Local(_))
+    }
+}
+
+impl fmt::Display for HookAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookAction::Local(command) => write!(f, "{}", command.join(" ")),
+            HookAction::Exec { command, .. } => write!(f, "exec {}", command.join(" ")),
+            HookAction::Job(manifest) => write!(f, "job {}", manifest),
+        }
+    }
+}
+
+/// A plain string or a table. Not `untagged`, as that would hide which field of the table is wrong.
+#[derive(Debug, Clone)]
+enum HookEntry {
+    Shell(String),
+    Hook(HookTable),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+struct HookTable {
+    command: Option<Vec<String>>,
+    exec: Option<Vec<String>>,
+    job: Option<String>,
+    selector: Option<String>,
+    container: Option<String>,
+    release: Option<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    timeout: Option<u64>,
+}
+
+impl<'de> de::Deserialize<'de> for HookEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct HookEntryVisitor;
+
+        impl<'de> Visitor<'de> for HookEntryVisitor {
+            type Value = HookEntry;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a shell command or a table with 'command', 'exec' or 'job'")
+            }
+
+            fn visit_str<E: de::Error>(self, script: &str) -> Result<HookEntry, E> {
+                Ok(HookEntry::Shell(script.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<HookEntry, A::Error> {
+                de::Deserialize::deserialize(MapAccessDeserializer::new(map)).map(HookEntry::Hook)
+            }
+        }
+
+        deserializer.deserialize_any(HookEntryVisitor)
+    }
+}
+
+impl TryFrom<HookEntry> for Hook {
+    type Error = String;
+
+    fn try_from(hook_entry: HookEntry) -> Result<Self, Self::Error> {
+        let hook = match hook_entry {
+            HookEntry::Shell(script) => {
+                return Ok(Hook {
+                    action: HookAction::Local(vec!["sh".to_string(), "-c".to_string(), script]),
+                    release: None,
+                    env: BTreeMap::new(),
+                    timeout: 300,
+                })
+            }
+            HookEntry::Hook(hook) => hook,
+        };
+
+        let action = match (hook.command, hook.exec, hook.job) {
+            (Some(command), None, None) if !command.is_empty() => HookAction::Local(command),
+            (None, Some(command), None) if !command.is_empty() => HookAction::Exec {
+                command,
+                selector: hook.selector,
+                container: hook.container,
+            },
+            (None, None, Some(manifest)) => HookAction::Job(manifest),
+            _ => return Err(
+                "a hook needs exactly one of 'command' or 'exec' (a non-empty command) or 'job'"
+                    .to_string(),
+            ),
+        };
+
+        // the job manifest defines the environment of its containers
+        if matches!(action, HookAction::Job(_)) && !hook.env.is_empty() {
+            return Err(
+                "'env' isn't passed to a 'job', please set it in the containers of its manifest"
+                    .to_string(),
+            );
+        }
+
+        Ok(Hook {
+            action,
+            release: hook.release,
+            env: hook.env,
+            timeout: hook.timeout.unwrap_or(300),
+        })
+    }
+}
+
+/// Image built against the docker daemon of minikube before the helm chart is deployed
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct Build {
+    pub image: String,
+    pub context: String,
+    pub dockerfile: Option<String>,
+
+    /// Defaults to a tag derived from the image id, so unchanged images don't trigger a rollout
+    pub tag: Option<String>,
+
+    #[serde(default)]
+    pub args: BTreeMap<String, String>,
+
+    /// Values path receiving the image name, e.g. "image.repository"
+    pub image_value: Option<String>,
+
+    /// Values path receiving the tag, e.g. "image.tag"
+    pub tag_value: Option<String>,
+}
+
+/// Image to make available in minikube, either loaded from the local docker daemon or built from `context`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(from = "ImageEntry")]
+pub struct Image {
+    pub name: String,
+    pub context: Option<String>,
+    pub dockerfile: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum ImageEntry {
+    Name(String),
+    Image {
+        name: String,
+        context: Option<String>,
+        dockerfile: Option<String>,
+    },
+}
+
+impl From<ImageEntry> for Image {
+    fn from(image_entry: ImageEntry) -> Self {
+        match image_entry {
+            ImageEntry::Name(name) => Image {
+                name,
+                context: None,
+                dockerfile: None,
+            },
+            ImageEntry::Image {
+                name,
+                context,
+                dockerfile,
+            } => Image {
+                name,
+                context,
+                dockerfile,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Minikube {
+    pub profile: Option<String>,
     pub cpus: Option<u8>,
     pub memory: Option<u16>,
+    pub driver: Option<String>,
+    pub kubernetes_version: Option<String>,
+    pub disk_size: Option<String>,
+    pub container_runtime: Option<String>,
+    pub nodes: Option<u8>,
+    pub mount: Option<String>,
+
+    #[serde(default)]
+    pub extra_config: Vec<String>,
+
+    #[serde(default)]
+    pub feature_gates: BTreeMap<String, bool>,
+
+    #[serde(default)]
+    pub insecure_registry: Vec<String>,
+
+    #[serde(default)]
+    pub start_args: Vec<String>,
 
     #[serde(default)]
     pub addons: Vec<String>,
+
+    /// Enables the ingress addon
+    #[serde(default)]
+    pub ingress: bool,
+
+    #[serde(default)]
+    pub down: DownMode,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DownMode {
+    Stop,
+    Pause,
+    #[default]
+    Delete,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Configuration {
     pub minikube: Option<Minikube>,
 
+    #[serde(default)]
+    pub helm: HelmOptions,
+
+    #[serde(default)]
+    pub port_forward: PortForwardOptions,
+
     #[serde(default)]
     pub default_values: BTreeMap<String, String>,
     pub helm_chart_repo: Option<Vec<HelmChartRepo>>,
     pub helmchart: Option<Vec<Helmchart>>,
+
+    #[serde(default)]
+    pub images: Vec<Image>,
+
+    #[serde(default)]
+    pub pre_up: Vec<Hook>,
+
+    /// The only hooks that get the forwarded ports, as they run once the ports are forwarded
+    #[serde(default)]
+    pub post_up: Vec<Hook>,
+
+    #[serde(default)]
+    pub pre_down: Vec<Hook>,
+}
+
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+
+    pub fn configuration(toml: &str) -> Result<Configuration, String> {
+        toml::from_str::<Configuration>(toml).map_err(|err| err.to_string())
+    }
+
+    /// Config file entry of the helm chart `name` from the repo "repo", followed by further fields
+    pub fn helmchart_toml(name: &str, fields: &str) -> String {
+        format!(
+            "[[helmchart]]\nhelm_chart_repo = \"repo\"\nname = \"{}\"\n{}\n",
+            name, fields
+        )
+    }
+
+    /// The helm chart "app" with further fields
+    pub fn helmchart(fields: &str) -> Result<Helmchart, String> {
+        let configuration = configuration(&helmchart_toml("app", fields))?;
+
+        Ok(configuration.helmchart.unwrap().remove(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::{configuration, helmchart};
+    use super::*;
+
+    #[test]
+    fn hook_from_string() {
+        let hooks = configuration(r#"pre_up = ["make seed"]"#).unwrap().pre_up;
+
+        assert_eq!(
+            hooks[0].action,
+            HookAction::Local(vec!["sh".into(), "-c".into(), "make seed".into()])
+        );
+        assert_eq!(hooks[0].timeout, 300);
+    }
+
+    #[test]
+    fn hook_from_table() {
+        let hooks = configuration(
+            r#"post_up = [
+                { command = ["npm", "run", "seed"], env = { A = "1" } },
+                { exec = ["pg_isready"], release = "db", selector = "app=db", container = "pg" },
+                { job = "job.yaml", release = "db", timeout = 60 },
+            ]"#,
+        )
+        .unwrap()
+        .post_up;
+
+        assert_eq!(
+            hooks[0].action,
+            HookAction::Local(vec!["npm".into(), "run".into(), "seed".into()])
+        );
+        assert_eq!(hooks[0].env.get("A").map(String::as_str), Some("1"));
+        assert_eq!(
+            hooks[1].action,
+            HookAction::Exec {
+                command: vec!["pg_isready".into()],
+                selector: Some("app=db".into()),
+                container: Some("pg".into()),
+            }
+        );
+        assert_eq!(hooks[1].release.as_deref(), Some("db"));
+        assert_eq!(hooks[2].action, HookAction::Job("job.yaml".into()));
+        assert_eq!(hooks[2].timeout, 60);
+    }
+
+    #[test]
+    fn hook_with_unknown_field() {
+        let err = configuration(r#"pre_up = [{ comand = ["x"] }]"#).unwrap_err();
+
+        assert!(err.contains("unknown field `comand`"), "{}", err);
+    }
+
+    #[test]
+    fn job_hook_without_env() {
+        let err =
+            configuration(r#"post_up = [{ job = "job.yaml", env = { A = "1" } }]"#).unwrap_err();
+
+        assert!(err.contains("'env' isn't passed to a 'job'"), "{}", err);
+    }
+
+    #[test]
+    fn hook_needs_exactly_one_action() {
+        for hook in [
+            r#"{ env = { A = "1" } }"#,
+            r#"{ command = [] }"#,
+            r#"{ command = ["x"], job = "job.yaml" }"#,
+        ] {
+            let err = configuration(&format!("pre_up = [{}]", hook)).unwrap_err();
+
+            assert!(err.contains("exactly one of"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn health_checks() {
+        let helmchart = helmchart(
+            r#"health_check = [
+                { http = "/health", port = 8080 },
+                { tcp = "postgres", timeout = 1, retries = 0 },
+                { exec = ["true"] },
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            helmchart.health_check[0].probe,
+            Probe::Http {
+                path: "/health".into(),
+                port: RemotePort::Number(8080),
+                status: 200,
+            }
+        );
+        assert_eq!(
+            (
+                helmchart.health_check[0].timeout,
+                helmchart.health_check[0].retries
+            ),
+            (5, 10)
+        );
+        assert_eq!(
+            helmchart.health_check[1].probe,
+            Probe::Tcp {
+                port: RemotePort::Name("postgres".into())
+            }
+        );
+        assert_eq!(
+            (
+                helmchart.health_check[1].timeout,
+                helmchart.health_check[1].retries
+            ),
+            (1, 0)
+        );
+        assert!(matches!(
+            helmchart.health_check[2].probe,
+            Probe::Exec { .. }
+        ));
+    }
+
+    #[test]
+    fn invalid_health_checks() {
+        for health_check in [
+            r#"{ http = "/health" }"#,
+            r#"{ http = "/health", tcp = 8080, port = 8080 }"#,
+            r#"{ exec = [] }"#,
+            r#"{ tcp = 8080, retry = 1 }"#,
+        ] {
+            assert!(
+                helmchart(&format!("health_check = [{}]", health_check)).is_err(),
+                "{}",
+                health_check
+            );
+        }
+    }
+
+    #[test]
+    fn namespace_from_helm_args() {
+        for (helm_args, namespace) in [
+            (r#"[]"#, None),
+            (r#"["--devel"]"#, None),
+            (r#"["--namespace", "apps"]"#, Some("apps")),
+            (r#"["--namespace=apps"]"#, Some("apps")),
+            (r#"["-n", "apps", "--devel"]"#, Some("apps")),
+        ] {
+            let helmchart = helmchart(&format!("helm_args = {}", helm_args)).unwrap();
+
+            assert_eq!(helmchart.namespace().as_deref(), namespace, "{}", helm_args);
+        }
+    }
+
+    #[test]
+    fn values_files_from_helm_args() {
+        let helmchart = helmchart(
+            r#"helm_args = ["-f", "a.yaml", "--devel", "--values=b.yaml,c.yaml", "--values", "d.yaml"]"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            helmchart.values_files(),
+            ["a.yaml", "b.yaml", "c.yaml", "d.yaml"]
+        );
+    }
 }
@@ -1,19 +1,35 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
 
 use crate::{
+    docker::Docker,
+    forward::{Forward, SupervisedPortForward},
+    health::{self, HealthCheckResult},
     helm::Helm,
+    hooks,
+    hosts::HostsFile,
     kubectl::Kubectl,
-    minikube::Minikube,
-    model::{Configuration, HelmChartRepo, Helmchart},
+    minikube::{Minikube, State},
+    model::{Build, Configuration, DownMode, HelmChartRepo, Helmchart, Hook, Image, RemotePort},
 };
 use anyhow::Ok;
 use colored::Colorize;
+use crossbeam_channel::Sender;
 use url::Url;
 
 pub struct Orchestrator {
     configuration: Configuration,
+    minikube_binary_path: PathBuf,
     helm_binary_path: PathBuf,
+    kubectl_binary_path: Option<PathBuf>,
+    docker_binary_path: Option<PathBuf>,
+    project_directory: PathBuf,
+    profile: String,
     minikube: Minikube,
+    docker: Option<Docker>,
     kubectl: Kubectl,
 }
 
@@ -22,26 +38,199 @@ impl Orchestrator {
         configuration: &Configuration,
         minikube_binary_path: &PathBuf,
         helm_binary_path: &PathBuf,
-        kubectl_binary_path: &PathBuf,
+        kubectl_binary_path: Option<&PathBuf>,
+        docker_binary_path: Option<&PathBuf>,
+        project_directory: &Path,
+        profile: &str,
     ) -> Orchestrator {
+        // minikube names the kubectl context after the profile
         Orchestrator {
             configuration: configuration.to_owned(),
+            minikube_binary_path: minikube_binary_path.to_owned(),
             helm_binary_path: helm_binary_path.to_owned(),
-            minikube: Minikube::new(configuration, minikube_binary_path),
-            kubectl: Kubectl::new(configuration, kubectl_binary_path),
+            kubectl_binary_path: kubectl_binary_path.cloned(),
+            docker_binary_path: docker_binary_path.cloned(),
+            project_directory: project_directory.to_owned(),
+            profile: profile.to_string(),
+            minikube: Minikube::new(configuration, minikube_binary_path, profile),
+            docker: docker_binary_path.map(Docker::new),
+            kubectl: Kubectl::new(configuration, kubectl_binary_path, profile),
+        }
+    }
+
+    /// Same orchestrator for a changed config file, e.g. after reloading it in watch mode
+    pub fn with_configuration(&self, configuration: &Configuration) -> Orchestrator {
+        Orchestrator {
+            kubectl: self.kubectl.with_configuration(configuration),
+            ..Orchestrator::new(
+                configuration,
+                &self.minikube_binary_path,
+                &self.helm_binary_path,
+                self.kubectl_binary_path.as_ref(),
+                self.docker_binary_path.as_ref(),
+                &self.project_directory,
+                &self.profile,
+            )
+        }
+    }
+
+    /// Start minikube or recover it, depending on its current state
+    pub fn bootstrap(&self, recreate: bool) -> anyhow::Result<()> {
+        match self.minikube.status()?.state() {
+            State::Running => {
+                println!("Minikube is already running! Skip start sequence...");
+
+                self.reconcile(recreate)
+            }
+            State::Misconfigured => {
+                println!("Minikube is running, but kubeconfig is misconfigured! Update context...");
+
+                self.minikube.update_context()?;
+                self.reconcile(recreate)
+            }
+            State::Paused => {
+                println!("Minikube is paused! Resume...");
+
+                self.minikube.unpause()
+            }
+            // 'minikube start' resumes a stopped cluster with all its images and volumes
+            State::Stopped => {
+                println!("Minikube is stopped! Resume...");
+
+                self.minikube.start()
+            }
+            State::Degraded => {
+                println!("Minikube is running, but Kubernetes is not! Restart...");
+
+                self.minikube.start()
+            }
+            State::Nonexistent => self.minikube.start(),
+            State::Broken(status) => Err(anyhow::anyhow!(
+                "Minikube is in an unexpected state ({}). Please check 'minikube status' or recreate it via 'kleinwuerfel down --delete'.",
+                status
+            )),
         }
     }
 
-    pub fn start(&self) -> anyhow::Result<()> {
-        self.minikube.start()
+    pub fn down(&self, mode: DownMode) -> anyhow::Result<()> {
+        match mode {
+            DownMode::Stop => self.minikube.stop()?,
+            DownMode::Pause => self.minikube.pause()?,
+            DownMode::Delete => self.minikube.cleanup()?,
+        }
+
+        self.remove_hosts();
+
+        Ok(())
+    }
+
+    /// Map the hosts of all helm charts to the minikube ip in the hosts file
+    pub fn update_hosts(&self) -> anyhow::Result<()> {
+        let mut hosts: Vec<String> = vec![];
+
+        for host in self
+            .configuration
+            .helmchart
+            .iter()
+            .flatten()
+            .flat_map(|helmchart| &helmchart.hosts)
+        {
+            if !hosts.contains(host) {
+                hosts.push(host.to_string());
+            }
+        }
+
+        // hosts removed from the config file shouldn't stay in the hosts file
+        if hosts.is_empty() {
+            self.remove_hosts();
+
+            return Ok(());
+        }
+
+        println!("{}", "Ingress hosts".bold().underline());
+
+        let ip = self.minikube.ip()?;
+        let hosts_file = HostsFile::new(&self.profile);
+
+        match hosts_file.update(&ip, &hosts) {
+            std::result::Result::Ok(()) => {
+                for host in &hosts {
+                    println!("{} -> {}", host, ip);
+                }
+            }
+            Err(err) => {
+                println!("{}", format!("{}", err).yellow());
+                println!(
+                    "{}",
+                    format!(
+                        "Please add these lines to '{}' yourself, e.g. with sudo:",
+                        hosts_file.path().display()
+                    )
+                    .yellow()
+                );
+                println!("{}", hosts_file.block(&ip, &hosts));
+            }
+        }
+
+        println!();
+
+        Ok(())
     }
 
-    pub fn cleanup(&self) -> anyhow::Result<()> {
-        self.minikube.cleanup()
+    /// Remove the hosts of the project from the hosts file, if there are any
+    fn remove_hosts(&self) {
+        let hosts_file = HostsFile::new(&self.profile);
+
+        match hosts_file.remove() {
+            std::result::Result::Ok(true) => println!(
+                "Removed the ingress hosts from '{}'.",
+                hosts_file.path().display()
+            ),
+            std::result::Result::Ok(false) => {}
+            Err(err) => println!(
+                "{}",
+                format!(
+                    "{} Please remove the block of profile '{}' yourself.",
+                    err, &self.profile
+                )
+                .yellow()
+            ),
+        }
     }
 
-    pub fn is_running(&self) -> anyhow::Result<bool> {
-        self.minikube.is_running()
+    /// Bring an already running cluster in line with the config file
+    fn reconcile(&self, recreate: bool) -> anyhow::Result<()> {
+        let missing_addons = self.minikube.missing_addons()?;
+
+        if !missing_addons.is_empty() {
+            println!("Enable missing addons: {}", missing_addons.join(", "));
+
+            self.minikube.enable_addons(&missing_addons)?;
+        }
+
+        let drift = self.minikube.drift()?;
+
+        if drift.is_empty() {
+            return Ok(());
+        }
+
+        println!(
+            "{}",
+            "The running cluster differs from the config file:".yellow()
+        );
+
+        for difference in &drift {
+            println!("  * {}", difference);
+        }
+
+        if recreate || confirm("Recreate the cluster? All deployments and volumes are lost.")? {
+            self.minikube.cleanup()?;
+            self.minikube.start()?;
+        } else {
+            println!("Keep the running cluster. Use 'up --recreate' to recreate it.");
+        }
+
+        Ok(())
     }
 
     pub fn add_helm_chart_repo(&self, helm_chart_repo: &HelmChartRepo) -> anyhow::Result<()> {
@@ -56,6 +245,7 @@ impl Orchestrator {
             helm_chart_repo,
             &self.configuration.default_values,
             &self.helm_binary_path,
+            &self.profile,
         );
 
         // Login failed
@@ -112,9 +302,251 @@ Maybe {} is the URL where you can relogin.
             helm_chart_repo,
             &self.configuration.default_values,
             &self.helm_binary_path,
+            &self.profile,
         );
 
-        helm.upgrade(&helmchart.helm_chart_repo, helmchart)?;
+        let options = self.configuration.helm.merge(&helmchart.helm);
+
+        let mut helmchart = helmchart.to_owned();
+
+        if let Some(build) = &helmchart.build {
+            let values = self.build_image(build)?;
+
+            helmchart.values.extend(values);
+        }
+
+        self.run_hooks(
+            "pre_deploy",
+            &helmchart.pre_deploy,
+            Some(&helmchart.name),
+            &[],
+        )?;
+
+        helm.upgrade(&helmchart.helm_chart_repo, &helmchart, &options)?;
+
+        self.run_hooks(
+            "post_deploy",
+            &helmchart.post_deploy,
+            Some(&helmchart.name),
+            &[],
+        )?;
+
+        println!();
+
+        Ok(())
+    }
+
+    pub fn deploy_all(
+        &self,
+        helmcharts: &[Helmchart],
+        helm_chart_repos: &[HelmChartRepo],
+    ) -> anyhow::Result<()> {
+        self.preload_images(helmcharts)?;
+
+        for helm_chart_repo in helm_chart_repos {
+            if helmcharts
+                .iter()
+                .any(|helmchart| helmchart.helm_chart_repo == helm_chart_repo.name)
+            {
+                self.add_helm_chart_repo(helm_chart_repo)?;
+            }
+        }
+
+        for helmchart in helmcharts {
+            self.deploy(helmchart, helm_chart_repos)?;
+        }
+
+        Ok(())
+    }
+
+    /// Build the image against the docker daemon of minikube and return the values referencing it
+    fn build_image(&self, build: &Build) -> anyhow::Result<BTreeMap<String, String>> {
+        let docker = self.docker.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("The binary 'docker' is missing in your $PATH. It's required to build images. Installation guide: https://docs.docker.com/get-docker/")
+        })?;
+
+        let docker = docker.with_environment(&self.minikube.docker_env()?);
+
+        let context = self.project_directory.join(&build.context);
+        let dockerfile = build
+            .dockerfile
+            .as_ref()
+            .map(|dockerfile| context.join(dockerfile));
+
+        println!("Build image '{}'", &build.image);
+
+        let tag = match &build.tag {
+            Some(tag) => {
+                let image = format!("{}:{}", &build.image, tag);
+
+                docker.build(&image, &context, dockerfile.as_deref(), &build.args)?;
+
+                tag.to_string()
+            }
+            None => {
+                let image = format!("{}:latest", &build.image);
+
+                docker.build(&image, &context, dockerfile.as_deref(), &build.args)?;
+
+                let image_id = docker.image_id(&image)?.ok_or_else(|| {
+                    anyhow::anyhow!("Cannot find image '{}' after building it.", image)
+                })?;
+
+                let tag = image_id
+                    .trim_start_matches("sha256:")
+                    .chars()
+                    .take(12)
+                    .collect::<String>();
+
+                docker.tag(&image, &format!("{}:{}", &build.image, tag))?;
+
+                tag
+            }
+        };
+
+        let mut values = BTreeMap::new();
+
+        if let Some(image_value) = &build.image_value {
+            values.insert(image_value.to_string(), build.image.to_string());
+        }
+
+        if let Some(tag_value) = &build.tag_value {
+            values.insert(tag_value.to_string(), tag);
+        }
+
+        Ok(values)
+    }
+
+    /// Make the global images and the images of the given helm charts available in minikube
+    pub fn preload_images(&self, helmcharts: &[Helmchart]) -> anyhow::Result<()> {
+        let mut images: Vec<&Image> = vec![];
+
+        for image in self
+            .configuration
+            .images
+            .iter()
+            .chain(helmcharts.iter().flat_map(|helmchart| &helmchart.images))
+        {
+            if !images.iter().any(|existing| existing.name == image.name) {
+                images.push(image);
+            }
+        }
+
+        if images.is_empty() {
+            return Ok(());
+        }
+
+        println!("{}", "Preload images".bold().underline());
+
+        let cluster_image_ids = self.minikube.image_ids()?;
+
+        for image in images {
+            if let Some(context) = &image.context {
+                println!("Build image '{}'", &image.name);
+
+                self.minikube.build_image(
+                    &image.name,
+                    &self.project_directory.join(context),
+                    image.dockerfile.as_deref(),
+                )?;
+
+                continue;
+            }
+
+            let local_image_id = match &self.docker {
+                Some(docker) => docker.image_id(&image.name)?,
+                None => None,
+            };
+
+            let present = local_image_id
+                .map(|id| id.trim_start_matches("sha256:").to_string())
+                .map(|id| cluster_image_ids.contains(&id))
+                .unwrap_or_default();
+
+            if present {
+                println!("Image '{}' is up to date. Skip loading...", &image.name);
+            } else {
+                println!("Load image '{}'", &image.name);
+
+                self.minikube.load_image(&image.name)?;
+            }
+        }
+
+        println!();
+
+        Ok(())
+    }
+
+    pub fn uninstall(&self, helmchart: &Helmchart, delete_pvcs: bool) -> anyhow::Result<()> {
+        println!(
+            "{}",
+            format!("Uninstall helm chart '{}'", &helmchart.name)
+                .bold()
+                .underline()
+        );
+
+        Helm::uninstall(&self.helm_binary_path, &self.profile, &helmchart.name)?;
+
+        if delete_pvcs {
+            self.kubectl.delete_persistent_volume_claims(helmchart)?;
+        }
+
+        println!();
+
+        Ok(())
+    }
+
+    pub fn rollback(&self, helmchart: &Helmchart, revision: Option<u32>) -> anyhow::Result<()> {
+        println!(
+            "{}",
+            format!("History of helm chart '{}'", &helmchart.name)
+                .bold()
+                .underline()
+        );
+
+        let history = Helm::history(&self.helm_binary_path, &self.profile, &helmchart.name)?;
+
+        println!(
+            "{:<10}{:<40}{:<16}{:<32}{:<16}FINGERPRINT",
+            "REVISION", "UPDATED", "STATUS", "CHART", "APP VERSION"
+        );
+
+        for revision in &history {
+            println!(
+                "{:<10}{:<40}{:<16}{:<32}{:<16}{}",
+                revision.revision,
+                revision.updated,
+                revision.status,
+                revision.chart,
+                revision.app_version,
+                revision.fingerprint(&history).unwrap_or("-")
+            );
+        }
+
+        if let Some(revision) = revision {
+            if !history.iter().any(|entry| entry.revision == revision) {
+                return Err(anyhow::anyhow!(
+                    "Revision {} of helm chart '{}' does not exist.",
+                    revision,
+                    &helmchart.name
+                ));
+            }
+        }
+
+        println!();
+        println!(
+            "{}",
+            format!("Roll back helm chart '{}'", &helmchart.name)
+                .bold()
+                .underline()
+        );
+
+        Helm::rollback(
+            &self.helm_binary_path,
+            &self.profile,
+            &helmchart.name,
+            revision,
+        )?;
 
         println!();
 
@@ -124,10 +556,151 @@ Maybe {} is the URL where you can relogin.
     pub fn list_deployed_helmcharts(&self) -> anyhow::Result<()> {
         println!("{}", "Deployed helm charts".bold().underline());
 
-        Helm::list()
+        Helm::list(&self.helm_binary_path, &self.profile)
     }
 
-    pub fn port_forward_all_helmcharts(&self) -> anyhow::Result<()> {
-        self.kubectl.port_forward_all_helmcharts()
+    /// Fail unless the cluster is running, without starting or changing it
+    pub fn ensure_running(&self) -> anyhow::Result<()> {
+        match self.minikube.status()?.state() {
+            State::Running => Ok(()),
+            State::Misconfigured => {
+                println!("Minikube is running, but kubeconfig is misconfigured! Update context...");
+
+                self.minikube.update_context()
+            }
+            state => Err(anyhow::anyhow!(
+                "Minikube is not running ({:?}). Please run 'kleinwuerfel up' first.",
+                state
+            )),
+        }
+    }
+
+    /// Forward the ports of all helm charts and run the 'post_up' hooks once they are forwarded
+    pub fn port_forward_all_helmcharts(
+        &self,
+        forwards_files: &[PathBuf],
+        health_checks: bool,
+    ) -> anyhow::Result<()> {
+        let post_up = |forwards: &[Forward]| {
+            // the port forwards keep running, even if a hook failed
+            if let Err(err) = self.run_stage_hooks("post_up", &self.configuration.post_up, forwards)
+            {
+                println!("{}", format!("{}", err).red());
+            }
+        };
+
+        self.kubectl
+            .port_forward_all_helmcharts(forwards_files, health_checks, Some(&post_up))
+    }
+
+    pub fn port_forward_helmcharts(
+        &self,
+        helmcharts: &[Helmchart],
+        forwards_files: &[PathBuf],
+        health_checks: bool,
+    ) -> anyhow::Result<()> {
+        self.kubectl
+            .port_forward_helmcharts(helmcharts, forwards_files, health_checks, None)
+    }
+
+    /// Run global hooks like 'pre_up' under a heading of their own
+    pub fn run_stage_hooks(
+        &self,
+        stage: &str,
+        hooks: &[Hook],
+        forwards: &[Forward],
+    ) -> anyhow::Result<()> {
+        if hooks.is_empty() {
+            return Ok(());
+        }
+
+        println!("{}", format!("Hooks '{}'", stage).bold().underline());
+
+        self.run_hooks(stage, hooks, None, forwards)?;
+
+        println!();
+
+        Ok(())
     }
+
+    /// Run the 'pre_down' hooks. Hooks running in the cluster are skipped if it doesn't run, e.g. because it's
+    /// stopped or paused, so they can't keep 'down' from deleting it.
+    pub fn run_pre_down_hooks(&self) -> anyhow::Result<()> {
+        let hooks = &self.configuration.pre_down;
+
+        let running = hooks.iter().any(|hook| hook.action.in_cluster())
+            && matches!(
+                self.minikube.status().map(|status| status.state()),
+                std::result::Result::Ok(State::Running)
+            );
+
+        let (hooks, skipped): (Vec<Hook>, Vec<Hook>) = hooks
+            .iter()
+            .cloned()
+            .partition(|hook| running || !hook.action.in_cluster());
+
+        for hook in &skipped {
+            println!(
+                "{}",
+                format!(
+                    "Minikube isn't running. Skip pre_down hook '{}'.",
+                    hook.action
+                )
+                .yellow()
+            );
+        }
+
+        self.run_stage_hooks("pre_down", &hooks, &[])
+    }
+
+    fn run_hooks(
+        &self,
+        stage: &str,
+        hooks: &[Hook],
+        release: Option<&str>,
+        forwards: &[Forward],
+    ) -> anyhow::Result<()> {
+        hooks::run(
+            &self.kubectl,
+            &self.project_directory,
+            &self.profile,
+            stage,
+            hooks,
+            release,
+            forwards,
+        )
+    }
+
+    /// Run the health checks of the helm chart in the background, see [`health::spawn`]
+    pub fn spawn_health_checks(
+        &self,
+        helmchart: &Helmchart,
+        local_ports: BTreeMap<RemotePort, u16>,
+        sender: &Sender<(String, Vec<HealthCheckResult>)>,
+    ) {
+        health::spawn(&self.kubectl, helmchart, local_ports, sender);
+    }
+
+    pub fn start_port_forwards(&self, helmchart: &Helmchart) -> Vec<SupervisedPortForward> {
+        SupervisedPortForward::start_all(&self.kubectl, helmchart)
+    }
+
+    pub fn supervise_port_forward(&self, port_forward: &mut SupervisedPortForward) -> bool {
+        port_forward.supervise(&self.kubectl)
+    }
+}
+
+/// Ask a yes/no question on the terminal, answering 'no' when not running interactively
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
 }
@@ -0,0 +1,131 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+
+use crate::{minikube::Minikube, model::Configuration};
+
+/// Directory kleinwuerfel keeps its state in, `$KLEINWUERFEL_STATE_DIR` takes precedence
+pub fn state_directory() -> anyhow::Result<PathBuf> {
+    if let Some(state_directory) = std::env::var_os("KLEINWUERFEL_STATE_DIR") {
+        return Ok(PathBuf::from(state_directory));
+    }
+
+    if let Some(state_home) = std::env::var_os("XDG_STATE_HOME") {
+        return Ok(PathBuf::from(state_home).join("kleinwuerfel"));
+    }
+
+    let home = std::env::var_os("HOME").ok_or_else(|| {
+        anyhow::anyhow!("Cannot determine the state directory, please set $KLEINWUERFEL_STATE_DIR")
+    })?;
+
+    Ok(PathBuf::from(home).join(".local/state/kleinwuerfel"))
+}
+
+/// The project directory is the directory containing the config file
+pub fn project_directory(config: &str) -> anyhow::Result<PathBuf> {
+    let config = fs::canonicalize(config)?;
+
+    Ok(config
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/")))
+}
+
+pub fn resolve_profile(configuration: &Configuration, project_directory: &Path) -> String {
+    if let Some(profile) = configuration
+        .minikube
+        .as_ref()
+        .and_then(|minikube| minikube.profile.to_owned())
+    {
+        return profile;
+    }
+
+    let name = project_directory
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    let profile = name
+        .chars()
+        .map(|character| {
+            if character.is_ascii_alphanumeric() {
+                character
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>()
+        .trim_matches('-')
+        .to_string();
+
+    if profile.is_empty() {
+        "kleinwuerfel".to_string()
+    } else {
+        profile
+    }
+}
+
+fn profiles_directory() -> anyhow::Result<PathBuf> {
+    Ok(state_directory()?.join("profiles"))
+}
+
+/// Remember which project owns the profile. Fails if another project already owns it.
+pub fn register(profile: &str, project_directory: &Path) -> anyhow::Result<()> {
+    let profiles_directory = profiles_directory()?;
+    let owner = profiles_directory.join(profile);
+
+    if let Ok(existing) = fs::read_to_string(&owner) {
+        if Path::new(existing.trim()) != project_directory {
+            return Err(anyhow::anyhow!(
+                "The minikube profile '{}' is already used by the project '{}'. Please set a different 'profile' in the [minikube] block.",
+                profile,
+                existing.trim()
+            ));
+        }
+    }
+
+    fs::create_dir_all(&profiles_directory)?;
+    fs::write(owner, project_directory.to_string_lossy().as_bytes())?;
+
+    Ok(())
+}
+
+pub fn unregister(profile: &str) -> anyhow::Result<()> {
+    let owner = profiles_directory()?.join(profile);
+
+    if owner.exists() {
+        fs::remove_file(owner)?;
+    }
+
+    Ok(())
+}
+
+fn owner(profile: &str) -> anyhow::Result<Option<String>> {
+    let owner = profiles_directory()?.join(profile);
+
+    Ok(fs::read_to_string(owner)
+        .ok()
+        .map(|owner| owner.trim().to_string()))
+}
+
+pub fn list(minikube_binary_path: &PathBuf) -> anyhow::Result<()> {
+    println!("{}", "Minikube profiles".bold().underline());
+
+    let profiles = Minikube::profiles(minikube_binary_path)?;
+
+    println!("{:<24}{:<16}PROJECT", "PROFILE", "STATUS");
+
+    for profile in &profiles {
+        println!(
+            "{:<24}{:<16}{}",
+            profile.name,
+            profile.status,
+            owner(&profile.name)?.unwrap_or_else(|| "-".to_string())
+        );
+    }
+
+    Ok(())
+}
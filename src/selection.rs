@@ -0,0 +1,302 @@
+use std::collections::BTreeSet;
+
+use crate::model::Helmchart;
+
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub only: Vec<String>,
+    pub skip: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+impl Selection {
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.skip.is_empty() && self.tags.is_empty()
+    }
+
+    /// Select the helmcharts to deploy, including the dependencies of every selected release.
+    /// The result is ordered so that dependencies are deployed before their dependents.
+    pub fn select(&self, helmcharts: &[Helmchart]) -> anyhow::Result<Vec<Helmchart>> {
+        for name in self.only.iter().chain(self.skip.iter()) {
+            find_helmchart(helmcharts, name)?;
+        }
+
+        for tag in &self.tags {
+            if !helmcharts
+                .iter()
+                .any(|helmchart| helmchart.tags.contains(tag))
+            {
+                return Err(anyhow::anyhow!(
+                    "No helm chart in the config file is tagged '{}'.",
+                    tag
+                ));
+            }
+        }
+
+        let mut selected = BTreeSet::new();
+
+        for helmchart in helmcharts {
+            let by_name = self.only.contains(&helmchart.name);
+            let by_tag = helmchart.tags.iter().any(|tag| self.tags.contains(tag));
+
+            if (self.only.is_empty() && self.tags.is_empty()) || by_name || by_tag {
+                self.add_with_dependencies(helmcharts, &helmchart.name, &mut selected)?;
+            }
+        }
+
+        let mut ordered = vec![];
+        let mut visiting = vec![];
+
+        for helmchart in helmcharts {
+            if selected.contains(&helmchart.name) {
+                order_by_dependencies(helmcharts, helmchart, &mut visiting, &mut ordered)?;
+            }
+        }
+
+        Ok(ordered
+            .into_iter()
+            .filter(|helmchart| selected.contains(&helmchart.name))
+            .collect())
+    }
+
+    fn add_with_dependencies(
+        &self,
+        helmcharts: &[Helmchart],
+        name: &str,
+        selected: &mut BTreeSet<String>,
+    ) -> anyhow::Result<()> {
+        if self.skip.iter().any(|skip| skip == name) || !selected.insert(name.to_string()) {
+            return Ok(());
+        }
+
+        let helmchart = find_helmchart(helmcharts, name)?;
+
+        for dependency in &helmchart.depends_on {
+            self.add_with_dependencies(helmcharts, dependency, selected)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn find_helmchart<'a>(
+    helmcharts: &'a [Helmchart],
+    name: &str,
+) -> anyhow::Result<&'a Helmchart> {
+    helmcharts
+        .iter()
+        .find(|helmchart| helmchart.name == name)
+        .ok_or_else(|| match suggest_helmchart(helmcharts, name) {
+            Some(suggestion) => anyhow::anyhow!(
+                "Helm chart '{}' not specified in config file. Did you mean '{}'?",
+                name,
+                suggestion
+            ),
+            None => anyhow::anyhow!("Helm chart '{}' not specified in config file.", name),
+        })
+}
+
+pub fn find_helmcharts(
+    helmcharts: &[Helmchart],
+    names: &[String],
+) -> anyhow::Result<Vec<Helmchart>> {
+    names
+        .iter()
+        .map(|name| find_helmchart(helmcharts, name).map(|helmchart| helmchart.to_owned()))
+        .collect()
+}
+
+fn suggest_helmchart<'a>(helmcharts: &'a [Helmchart], name: &str) -> Option<&'a str> {
+    helmcharts
+        .iter()
+        .map(|helmchart| {
+            (
+                strsim::jaro_winkler(name, &helmchart.name),
+                helmchart.name.as_str(),
+            )
+        })
+        .filter(|(confidence, _)| *confidence > 0.8)
+        .max_by(|(left, _), (right, _)| left.total_cmp(right))
+        .map(|(_, name)| name)
+}
+
+fn order_by_dependencies(
+    helmcharts: &[Helmchart],
+    helmchart: &Helmchart,
+    visiting: &mut Vec<String>,
+    ordered: &mut Vec<Helmchart>,
+) -> anyhow::Result<()> {
+    if ordered.iter().any(|ordered| ordered.name == helmchart.name) {
+        return Ok(());
+    }
+
+    if visiting.contains(&helmchart.name) {
+        visiting.push(helmchart.name.to_string());
+
+        return Err(anyhow::anyhow!(
+            "Circular dependency between helm charts: {}",
+            visiting.join(" -> ")
+        ));
+    }
+
+    visiting.push(helmchart.name.to_string());
+
+    for dependency in &helmchart.depends_on {
+        let dependency = find_helmchart(helmcharts, dependency)?;
+
+        order_by_dependencies(helmcharts, dependency, visiting, ordered)?;
+    }
+
+    visiting.pop();
+    ordered.push(helmchart.to_owned());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::fixtures::{configuration, helmchart_toml};
+
+    /// Helm charts given as name, tags and dependencies
+    fn helmcharts(helmcharts: &[(&str, &[&str], &[&str])]) -> Vec<Helmchart> {
+        let toml = helmcharts
+            .iter()
+            .map(|(name, tags, depends_on)| {
+                helmchart_toml(
+                    name,
+                    &format!("tags = {:?}\ndepends_on = {:?}", tags, depends_on),
+                )
+            })
+            .collect::<String>();
+
+        configuration(&toml).unwrap().helmchart.unwrap()
+    }
+
+    fn names(helmcharts: &[Helmchart]) -> Vec<&str> {
+        helmcharts
+            .iter()
+            .map(|helmchart| helmchart.name.as_str())
+            .collect()
+    }
+
+    fn selection(only: &[&str], skip: &[&str], tags: &[&str]) -> Selection {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+
+        Selection {
+            only: strings(only),
+            skip: strings(skip),
+            tags: strings(tags),
+        }
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let helmcharts = helmcharts(&[
+            ("frontend", &[], &["backend"]),
+            ("backend", &[], &["db"]),
+            ("db", &[], &[]),
+        ]);
+
+        let selected = Selection::default().select(&helmcharts).unwrap();
+
+        assert_eq!(names(&selected), ["db", "backend", "frontend"]);
+    }
+
+    #[test]
+    fn only_includes_dependencies() {
+        let helmcharts = helmcharts(&[
+            ("frontend", &[], &["backend"]),
+            ("backend", &[], &[]),
+            ("monitoring", &[], &[]),
+        ]);
+
+        let selected = selection(&["frontend"], &[], &[])
+            .select(&helmcharts)
+            .unwrap();
+
+        assert_eq!(names(&selected), ["backend", "frontend"]);
+    }
+
+    #[test]
+    fn tags_and_skip() {
+        let helmcharts = helmcharts(&[
+            ("api", &["backend"], &["db"]),
+            ("worker", &["backend"], &[]),
+            ("db", &[], &[]),
+            ("web", &["frontend"], &[]),
+        ]);
+
+        let selected = selection(&[], &["db"], &["backend"])
+            .select(&helmcharts)
+            .unwrap();
+
+        assert_eq!(names(&selected), ["api", "worker"]);
+    }
+
+    #[test]
+    fn circular_dependencies() {
+        let helmcharts = helmcharts(&[("a", &[], &["b"]), ("b", &[], &["c"]), ("c", &[], &["a"])]);
+
+        let err = Selection::default().select(&helmcharts).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Circular dependency between helm charts: a -> b -> c -> a"
+        );
+    }
+
+    #[test]
+    fn unknown_helmchart() {
+        let helmcharts = helmcharts(&[("backend", &[], &[])]);
+
+        let err = selection(&[], &["db"], &[])
+            .select(&helmcharts)
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Helm chart 'db' not specified in config file."
+        );
+    }
+
+    #[test]
+    fn unknown_tag() {
+        let helmcharts = helmcharts(&[("backend", &["api"], &[])]);
+
+        let err = selection(&[], &[], &["apj"])
+            .select(&helmcharts)
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "No helm chart in the config file is tagged 'apj'."
+        );
+    }
+
+    #[test]
+    fn unknown_helmchart_with_suggestion() {
+        let helmcharts = helmcharts(&[("backend", &[], &[])]);
+
+        let err = selection(&["backnd"], &[], &[])
+            .select(&helmcharts)
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Helm chart 'backnd' not specified in config file. Did you mean 'backend'?"
+        );
+    }
+
+    #[test]
+    fn unknown_dependency() {
+        let helmcharts = helmcharts(&[("app", &[], &["missing"])]);
+
+        let err = Selection::default().select(&helmcharts).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Helm chart 'missing' not specified in config file."
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    ops::{Deref, DerefMut},
+    os::unix::net::UnixStream,
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Output},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use colored::Colorize;
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use lazy_static::lazy_static;
+
+/// Signals which shut kleinwuerfel down
+const SIGNALS: [i32; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// How long child processes get to exit after SIGTERM before they are killed
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The signal which requested the shutdown, 0 before
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+lazy_static! {
+    /// Tracked child processes and whether they lead a process group of their own
+    static ref CHILDREN: Mutex<BTreeMap<u32, bool>> = Mutex::new(BTreeMap::new());
+    static ref SUBSCRIBERS: Mutex<Vec<Sender<()>>> = Mutex::new(vec![]);
+}
+
+/// Handle SIGINT, SIGTERM and SIGHUP for the whole process. On the first signal, all subscribers are
+/// notified and all tracked child processes are terminated, escalating to SIGKILL after a grace period.
+/// A second signal kills them right away.
+pub fn install() -> anyhow::Result<()> {
+    let (mut receiver, sender) = UnixStream::pair()?;
+
+    for signal in SIGNALS {
+        let sender = sender.try_clone()?;
+
+        // the signal handler itself only writes the signal to the pipe, which is async-signal-safe
+        unsafe {
+            signal_hook_registry::register(signal, move || {
+                let _ = (&sender).write(&[signal as u8]);
+            })?;
+        }
+    }
+
+    thread::spawn(move || {
+        let mut signal = [0; 1];
+
+        while receiver.read_exact(&mut signal).is_ok() {
+            let signal = signal[0] as i32;
+
+            // a second signal must get through while the first one waits for the child processes
+            thread::spawn(move || handle_signal(signal));
+        }
+    });
+
+    Ok(())
+}
+
+/// Exit code after a shutdown, 128 plus the signal like shells use, e.g. 130 for SIGINT and 143 for SIGTERM
+pub fn exit_code() -> i32 {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => 128 + libc::SIGINT,
+        signal => 128 + signal,
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// The returned channel receives a message or disconnects once shutdown is requested
+pub fn subscribe() -> Receiver<()> {
+    let (sender, receiver) = bounded(1);
+
+    if !requested() {
+        lock(&SUBSCRIBERS).push(sender);
+    }
+
+    receiver
+}
+
+fn handle_signal(signal: i32) {
+    let _ = SIGNAL.compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst);
+
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        println!("{}", "Kill all child processes...".yellow());
+
+        signal_children(libc::SIGKILL);
+
+        std::process::exit(exit_code());
+    }
+
+    println!();
+    println!("{}", "Shutting down...".yellow());
+
+    // subscribers which are gone don't stop anything
+    let notified = lock(&SUBSCRIBERS)
+        .drain(..)
+        .filter(|subscriber| !matches!(subscriber.try_send(()), Err(TrySendError::Disconnected(_))))
+        .count();
+
+    signal_children(libc::SIGTERM);
+
+    let deadline = Instant::now() + GRACE_PERIOD;
+
+    while !lock(&CHILDREN).is_empty() {
+        if Instant::now() > deadline {
+            println!(
+                "{}",
+                "Child processes didn't exit in time. Kill them...".yellow()
+            );
+
+            signal_children(libc::SIGKILL);
+
+            std::process::exit(exit_code());
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // subscribers wind down on their own and main exits afterwards
+    if notified == 0 {
+        std::process::exit(exit_code());
+    }
+}
+
+fn signal_children(signal_number: i32) {
+    let children = lock(&CHILDREN).clone();
+
+    for (pid, group) in children {
+        let _ = if group {
+            signal_group(pid, signal_number)
+        } else {
+            signal(pid, signal_number)
+        };
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    // the data stays consistent even if a thread panicked while holding the lock
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub trait SpawnTracked {
+    /// Spawn a child process which is terminated on shutdown. Fails once shutdown is requested.
+    fn spawn_tracked(&mut self) -> anyhow::Result<TrackedChild>;
+
+    /// Like `spawn_tracked`, but in a process group of its own which is terminated as a whole, so the
+    /// processes started by e.g. 'sh -c' are terminated too
+    fn spawn_tracked_group(&mut self) -> anyhow::Result<TrackedChild>;
+}
+
+impl SpawnTracked for Command {
+    fn spawn_tracked(&mut self) -> anyhow::Result<TrackedChild> {
+        spawn(self, false)
+    }
+
+    fn spawn_tracked_group(&mut self) -> anyhow::Result<TrackedChild> {
+        spawn(self.process_group(0), true)
+    }
+}
+
+fn spawn(command: &mut Command, group: bool) -> anyhow::Result<TrackedChild> {
+    if requested() {
+        return Err(anyhow::anyhow!(
+            "Shutting down, '{}' isn't started anymore.",
+            command.get_program().to_string_lossy()
+        ));
+    }
+
+    let child = command.spawn()?;
+
+    lock(&CHILDREN).insert(child.id(), group);
+
+    Ok(TrackedChild { child: Some(child) })
+}
+
+/// Child process which is untracked once it's dropped
+pub struct TrackedChild {
+    child: Option<Child>,
+}
+
+impl TrackedChild {
+    pub fn wait_with_output(mut self) -> io::Result<Output> {
+        match self.child.take() {
+            Some(child) => {
+                let pid = child.id();
+                let output = child.wait_with_output();
+
+                lock(&CHILDREN).remove(&pid);
+
+                output
+            }
+            None => Err(io::Error::other("child process is gone")),
+        }
+    }
+
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.deref_mut().wait();
+
+        if status.is_ok() {
+            lock(&CHILDREN).remove(&self.id());
+        }
+
+        status
+    }
+}
+
+impl Deref for TrackedChild {
+    type Target = Child;
+
+    fn deref(&self) -> &Self::Target {
+        self.child.as_ref().expect("child process is gone")
+    }
+}
+
+impl DerefMut for TrackedChild {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.child.as_mut().expect("child process is gone")
+    }
+}
+
+impl Drop for TrackedChild {
+    fn drop(&mut self) {
+        if let Some(child) = &self.child {
+            lock(&CHILDREN).remove(&child.id());
+        }
+    }
+}
+
+pub fn is_alive(pid: u32) -> bool {
+    // signal 0 only checks whether the process exists
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+/// Send the signal, e.g. `libc::SIGTERM`
+pub fn signal(pid: u32, signal_number: i32) -> anyhow::Result<()> {
+    kill(pid as libc::pid_t, signal_number)
+}
+
+/// Send the signal to all processes of the process group, e.g. a process started with `process_group(0)`
+/// and its children
+pub fn signal_group(pgid: u32, signal_number: i32) -> anyhow::Result<()> {
+    // a negative pid addresses the process group
+    kill(-(pgid as libc::pid_t), signal_number)
+}
+
+fn kill(pid: libc::pid_t, signal_number: i32) -> anyhow::Result<()> {
+    if unsafe { libc::kill(pid, signal_number) } == 0 {
+        return Ok(());
+    }
+
+    match io::Error::last_os_error() {
+        // the process exited in the meantime
+        err if err.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        err => Err(err.into()),
+    }
+}
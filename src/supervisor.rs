@@ -0,0 +1,304 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use colored::Colorize;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    forward::{print_forwards, Forward},
+    profile::state_directory,
+    shutdown::{is_alive, signal_group},
+};
+
+/// How long to wait for the background process to report its forwards
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for the background process to stop before killing it
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// What the background process was started with, so `restart` can start it the same way again
+#[derive(Serialize, Deserialize)]
+struct Arguments {
+    config: PathBuf,
+    releases: Vec<String>,
+    forwards_file: Option<PathBuf>,
+}
+
+/// Port forwarding running in a background process ('kleinwuerfel forward --detach'), tracked by a
+/// pid file in the state directory of the project
+pub struct Supervisor {
+    directory: PathBuf,
+}
+
+impl Supervisor {
+    pub fn new(profile: &str) -> anyhow::Result<Self> {
+        Ok(Supervisor {
+            directory: state_directory()?.join("forwards").join(profile),
+        })
+    }
+
+    fn pid_file(&self) -> PathBuf {
+        self.directory.join("pid")
+    }
+
+    fn log_file(&self) -> PathBuf {
+        self.directory.join("log")
+    }
+
+    fn arguments_file(&self) -> PathBuf {
+        self.directory.join("arguments.json")
+    }
+
+    /// The background process keeps this file up to date, so `status` can show the forwards
+    pub fn forwards_file(&self) -> PathBuf {
+        self.directory.join("forwards.json")
+    }
+
+    /// Pid of the background process, if it's still running. Stale state is removed.
+    fn running_pid(&self) -> anyhow::Result<Option<u32>> {
+        let pid = match fs::read_to_string(self.pid_file()) {
+            Ok(pid) => pid.trim().parse::<u32>().ok(),
+            Err(_) => return Ok(None),
+        };
+
+        match pid {
+            Some(pid) if is_supervisor(pid) => Ok(Some(pid)),
+            _ => {
+                self.remove_state()?;
+
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn detach(
+        &self,
+        config: &Path,
+        releases: &[String],
+        forwards_file: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        if let Some(pid) = self.running_pid()? {
+            return Err(anyhow::anyhow!(
+                "Port forwarding already runs in the background (pid {}). Stop it with 'kleinwuerfel forward stop' first.",
+                pid
+            ));
+        }
+
+        fs::create_dir_all(&self.directory)?;
+
+        let log = File::create(self.log_file())?;
+
+        let arguments = Arguments {
+            config: fs::canonicalize(config)?,
+            releases: releases.to_vec(),
+            forwards_file: forwards_file.map(std::path::absolute).transpose()?,
+        };
+
+        let mut command = Command::new(std::env::current_exe()?);
+        command
+            .arg("--config")
+            .arg(&arguments.config)
+            .arg("forward")
+            .arg("--supervisor");
+
+        if let Some(forwards_file) = &arguments.forwards_file {
+            command.arg("--forwards-file").arg(forwards_file);
+        }
+
+        command
+            .args(&arguments.releases)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log);
+
+        // a process group of its own keeps Ctrl+C and the closing terminal away from it
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+        let mut child = command.spawn()?;
+
+        fs::write(self.pid_file(), child.id().to_string())?;
+        fs::write(self.arguments_file(), serde_json::to_string(&arguments)?)?;
+
+        let deadline = Instant::now() + START_TIMEOUT;
+
+        let forwards = loop {
+            if let Some(forwards) = self.read_forwards() {
+                break Some(forwards);
+            }
+
+            if child.try_wait()?.is_some() {
+                self.remove_state()?;
+
+                return Err(anyhow::anyhow!(
+                    "Port forwarding in the background failed. Please check the log: {}",
+                    self.log_file().display()
+                ));
+            }
+
+            if Instant::now() > deadline {
+                break None;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        println!(
+            "Port forwarding runs in the background (pid {}).",
+            child.id()
+        );
+
+        match forwards {
+            Some(forwards) => print_forwards(&forwards),
+            None => println!(
+                "{}",
+                "The forwarded ports weren't reported yet, check them with 'kleinwuerfel forward status'."
+                    .yellow()
+            ),
+        }
+
+        println!("Log: {}", self.log_file().display());
+        println!("Stop it with 'kleinwuerfel forward stop'.");
+
+        Ok(())
+    }
+
+    pub fn status(&self) -> anyhow::Result<()> {
+        println!("{}", "Port forwarding".bold().underline());
+
+        match self.running_pid()? {
+            Some(pid) => {
+                println!("Runs in the background (pid {}).", pid);
+
+                print_forwards(&self.read_forwards().unwrap_or_default());
+
+                println!("Log: {}", self.log_file().display());
+            }
+            None => println!("Doesn't run in the background."),
+        }
+
+        Ok(())
+    }
+
+    pub fn stop(&self) -> anyhow::Result<()> {
+        let pid = match self.running_pid()? {
+            Some(pid) => pid,
+            None => {
+                println!("Port forwarding doesn't run in the background.");
+
+                return Ok(());
+            }
+        };
+
+        println!("Stop port forwarding in the background (pid {})...", pid);
+
+        // the port forwards run in the process group of the background process
+        signal_group(pid, libc::SIGTERM)?;
+
+        let deadline = Instant::now() + STOP_TIMEOUT;
+
+        while is_alive(pid) {
+            if Instant::now() > deadline {
+                println!(
+                    "{}",
+                    format!("Process {} didn't stop in time. Kill it...", pid).yellow()
+                );
+
+                signal_group(pid, libc::SIGKILL)?;
+
+                break;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        self.remove_state()
+    }
+
+    /// Restart the background process with the arguments it was started with, e.g. after a rollback
+    /// replaced the pods of `release`. Returns false if it doesn't run or doesn't forward the release.
+    pub fn restart(&self, release: &str) -> anyhow::Result<bool> {
+        if self.running_pid()?.is_none() {
+            return Ok(false);
+        }
+
+        let arguments = fs::read_to_string(self.arguments_file())
+            .ok()
+            .and_then(|content| serde_json::from_str::<Arguments>(&content).ok())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Can't restart the port forwarding in the background, it was started by an older version. Please restart it with 'kleinwuerfel forward stop' and 'kleinwuerfel forward --detach'."
+                )
+            })?;
+
+        if !arguments.releases.is_empty() && !arguments.releases.iter().any(|name| name == release)
+        {
+            return Ok(false);
+        }
+
+        self.stop()?;
+        self.detach(
+            &arguments.config,
+            &arguments.releases,
+            arguments.forwards_file.as_deref(),
+        )?;
+
+        Ok(true)
+    }
+
+    /// Called by the background process itself after the port forwarding stopped
+    pub fn finish(&self) -> anyhow::Result<()> {
+        let pid = fs::read_to_string(self.pid_file())
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok());
+
+        if pid == Some(std::process::id()) {
+            self.remove_state()?;
+        }
+
+        Ok(())
+    }
+
+    fn read_forwards(&self) -> Option<Vec<Forward>> {
+        let content = fs::read_to_string(self.forwards_file()).ok()?;
+
+        // the file might be read while it's being written
+        serde_json::from_str(&content).ok()
+    }
+
+    fn remove_state(&self) -> anyhow::Result<()> {
+        for file in [self.pid_file(), self.forwards_file(), self.arguments_file()] {
+            if file.exists() {
+                fs::remove_file(file)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The pid file might be stale and its pid reused by another process since, which must not be signalled
+fn is_supervisor(pid: u32) -> bool {
+    Command::new("ps")
+        .arg("-o")
+        .arg("args=")
+        .arg("-p")
+        .arg(pid.to_string())
+        .stderr(Stdio::null())
+        .output()
+        .map(|output| {
+            let arguments = String::from_utf8_lossy(&output.stdout);
+
+            output.status.success()
+                && arguments.contains(" forward ")
+                && arguments.contains("--supervisor")
+        })
+        .unwrap_or(false)
+}
@@ -0,0 +1,503 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use colored::Colorize;
+use crossbeam_channel::{select, tick, unbounded, Receiver, Sender};
+use notify::{Event, EventKind, RecursiveMode, Watcher as _};
+
+use crate::{
+    forward::{
+        all_forwards, local_ports, print_forwards, write_forwards, Forward, SupervisedPortForward,
+        SUPERVISE_INTERVAL,
+    },
+    health::{print_results, HealthCheckResult},
+    kubectl::check_local_ports,
+    model::{Configuration, Helmchart},
+    orchestrator::Orchestrator,
+    selection::Selection,
+    shutdown,
+};
+
+/// Changes arriving within this window are handled together, e.g. a `git checkout` touching many files
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Keeps the port forwards alive and redeploys the affected helm charts whenever the config file,
+/// one of the values files or one of the image build contexts changes
+pub struct Watch {
+    orchestrator: Orchestrator,
+    configuration: Configuration,
+    /// Releases left out by '--only', '--skip' and '--tag' aren't redeployed either
+    selection: Selection,
+    config_path: PathBuf,
+    project_directory: PathBuf,
+    forwards_file: Option<PathBuf>,
+    port_forwards: BTreeMap<String, Vec<SupervisedPortForward>>,
+    forwards: BTreeMap<String, Vec<Forward>>,
+    health_sender: Sender<(String, Vec<HealthCheckResult>)>,
+    health_receiver: Receiver<(String, Vec<HealthCheckResult>)>,
+    /// Helm charts whose health checks still run, the results are shown once all of them are done
+    pending_health_checks: BTreeSet<String>,
+    health_results: BTreeMap<String, Vec<HealthCheckResult>>,
+}
+
+impl Watch {
+    pub fn new(
+        orchestrator: Orchestrator,
+        configuration: &Configuration,
+        selection: &Selection,
+        config_path: &str,
+        project_directory: &Path,
+        forwards_file: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let (health_sender, health_receiver) = unbounded();
+
+        Ok(Watch {
+            orchestrator,
+            configuration: configuration.to_owned(),
+            selection: selection.to_owned(),
+            config_path: fs::canonicalize(config_path)?,
+            project_directory: project_directory.to_owned(),
+            forwards_file: forwards_file.map(Path::to_path_buf),
+            port_forwards: BTreeMap::new(),
+            forwards: BTreeMap::new(),
+            health_sender,
+            health_receiver,
+            pending_health_checks: BTreeSet::new(),
+            health_results: BTreeMap::new(),
+        })
+    }
+
+    pub fn run(mut self) -> anyhow::Result<()> {
+        let shutdown_receiver = shutdown::subscribe();
+
+        let (event_sender, event_receiver) = unbounded();
+        let mut watcher = notify::recommended_watcher(event_sender)?;
+
+        let mut watched_directories = self.watched_directories();
+
+        for (directory, recursive_mode) in &watched_directories {
+            watcher.watch(directory, *recursive_mode)?;
+        }
+
+        println!();
+        println!("{}", "Forwarding ports".bold().underline());
+        println!("Watching for changes. Press Ctrl+C to stop.");
+        println!();
+
+        check_local_ports(&self.helmcharts())?;
+
+        for helmchart in self.helmcharts() {
+            self.start_port_forward(&helmchart)?;
+        }
+
+        self.report_forwards()?;
+        self.start_health_checks(&self.helmcharts());
+
+        // the port forwards keep running, even if a hook failed
+        if let Err(err) = self.orchestrator.run_stage_hooks(
+            "post_up",
+            &self.configuration.post_up,
+            &self
+                .forwards
+                .values()
+                .flatten()
+                .cloned()
+                .collect::<Vec<Forward>>(),
+        ) {
+            println!("{}", format!("{}", err).red());
+        }
+
+        let supervise = tick(SUPERVISE_INTERVAL);
+        let health_receiver = self.health_receiver.clone();
+
+        loop {
+            select! {
+                recv(shutdown_receiver) -> _ => break,
+                recv(supervise) -> _ => self.supervise_port_forwards()?,
+                recv(health_receiver) -> message => {
+                    if let Ok((release, results)) = message {
+                        self.collect_health_results(&release, results);
+                    }
+                }
+                recv(event_receiver) -> event => {
+                    let mut changed_paths = BTreeSet::new();
+
+                    collect_changed_paths(event?, &mut changed_paths);
+
+                    while let Ok(event) = event_receiver.recv_timeout(DEBOUNCE) {
+                        collect_changed_paths(event, &mut changed_paths);
+                    }
+
+                    if changed_paths.is_empty() {
+                        continue;
+                    }
+
+                    if let Err(err) = self.handle_changes(&changed_paths) {
+                        println!("{}", format!("{}", err).red());
+                    }
+
+                    // the config file might reference new values files or build contexts
+                    let directories = self.watched_directories();
+
+                    for (directory, recursive_mode) in &watched_directories {
+                        if directories.get(directory) != Some(recursive_mode) {
+                            let _ = watcher.unwatch(directory);
+                        }
+                    }
+
+                    for (directory, recursive_mode) in &directories {
+                        if watched_directories.get(directory) != Some(recursive_mode) {
+                            watcher.watch(directory, *recursive_mode)?;
+                        }
+                    }
+
+                    watched_directories = directories;
+                }
+            }
+        }
+
+        for port_forward in self.port_forwards.values_mut().flatten() {
+            port_forward.stop();
+        }
+
+        Ok(())
+    }
+
+    fn helmcharts(&self) -> Vec<Helmchart> {
+        self.configuration.helmchart.to_owned().unwrap_or_default()
+    }
+
+    /// The build contexts with everything below them, and the directories of the config file and the values
+    /// files. Editors replace files instead of writing them, so the directory is watched instead of the file.
+    fn watched_directories(&self) -> BTreeMap<PathBuf, RecursiveMode> {
+        let mut directories = self
+            .contexts()
+            .into_iter()
+            .map(|context| (context, RecursiveMode::Recursive))
+            .collect::<BTreeMap<PathBuf, RecursiveMode>>();
+
+        let file_directories = self
+            .helmcharts()
+            .iter()
+            .flat_map(values_files)
+            .filter_map(|values_file| values_file.parent().map(Path::to_path_buf))
+            .chain([self.project_directory.to_owned()])
+            .collect::<Vec<PathBuf>>();
+
+        for directory in file_directories {
+            let covered = directories.iter().any(|(watched, recursive_mode)| {
+                *recursive_mode == RecursiveMode::Recursive && directory.starts_with(watched)
+            });
+
+            if !covered {
+                directories.insert(directory, RecursiveMode::NonRecursive);
+            }
+        }
+
+        directories
+    }
+
+    /// Existing build contexts of global images, images of helm charts and helm chart builds
+    fn contexts(&self) -> BTreeSet<PathBuf> {
+        let helmcharts = self.helmcharts();
+
+        let image_contexts = self
+            .configuration
+            .images
+            .iter()
+            .chain(helmcharts.iter().flat_map(|helmchart| &helmchart.images))
+            .filter_map(|image| image.context.as_ref());
+
+        let build_contexts = helmcharts
+            .iter()
+            .filter_map(|helmchart| helmchart.build.as_ref())
+            .map(|build| &build.context);
+
+        image_contexts
+            .chain(build_contexts)
+            .filter_map(|context| fs::canonicalize(self.project_directory.join(context)).ok())
+            .collect()
+    }
+
+    fn handle_changes(&mut self, changed_paths: &BTreeSet<PathBuf>) -> anyhow::Result<()> {
+        let mut affected = BTreeSet::new();
+
+        if changed_paths.contains(&self.config_path) {
+            affected.extend(self.reload_configuration()?);
+        }
+
+        let helmcharts = self.helmcharts();
+
+        for helmchart in &helmcharts {
+            let contexts = helmchart
+                .images
+                .iter()
+                .filter_map(|image| image.context.as_ref())
+                .chain(helmchart.build.iter().map(|build| &build.context));
+
+            for context in contexts {
+                if let Ok(context) = fs::canonicalize(self.project_directory.join(context)) {
+                    if changed_paths.iter().any(|path| path.starts_with(&context)) {
+                        affected.insert(helmchart.name.to_string());
+                    }
+                }
+            }
+
+            if values_files(helmchart)
+                .iter()
+                .any(|values_file| changed_paths.contains(values_file))
+            {
+                affected.insert(helmchart.name.to_string());
+            }
+        }
+
+        let selected = self
+            .selection
+            .select(&helmcharts)?
+            .into_iter()
+            .map(|helmchart| helmchart.name)
+            .collect::<BTreeSet<String>>();
+
+        affected.retain(|name| selected.contains(name));
+
+        let global_image_changed = self
+            .configuration
+            .images
+            .iter()
+            .filter_map(|image| image.context.as_ref())
+            .filter_map(|context| fs::canonicalize(self.project_directory.join(context)).ok())
+            .any(|context| changed_paths.iter().any(|path| path.starts_with(&context)));
+
+        if global_image_changed && affected.is_empty() {
+            self.orchestrator.preload_images(&[])?;
+        }
+
+        if affected.is_empty() {
+            return Ok(());
+        }
+
+        let affected = helmcharts
+            .into_iter()
+            .filter(|helmchart| affected.contains(&helmchart.name))
+            .collect::<Vec<Helmchart>>();
+
+        println!(
+            "{}",
+            format!(
+                "Changes detected, redeploy: {}",
+                affected
+                    .iter()
+                    .map(|helmchart| helmchart.name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            )
+            .bold()
+            .underline()
+        );
+        println!();
+
+        let helm_chart_repos = self
+            .configuration
+            .helm_chart_repo
+            .to_owned()
+            .unwrap_or_default();
+
+        for helmchart in &affected {
+            self.stop_port_forward(&helmchart.name);
+        }
+
+        let deployed = self.orchestrator.deploy_all(&affected, &helm_chart_repos);
+
+        // the port forwards come back even if the deployment failed, e.g. to the previous revision
+        for helmchart in &affected {
+            self.start_port_forward(helmchart)?;
+        }
+
+        self.report_forwards()?;
+        deployed?;
+        self.start_health_checks(&affected);
+
+        Ok(())
+    }
+
+    /// Reload the config file and return the names of all helm charts which changed
+    fn reload_configuration(&mut self) -> anyhow::Result<BTreeSet<String>> {
+        let content = fs::read_to_string(&self.config_path)?;
+        let configuration = toml::from_str::<Configuration>(&content)
+            .map_err(|err| anyhow::anyhow!("Cannot reload config file: {}", err))?;
+
+        crate::helm::validate(&configuration)?;
+        crate::forward::validate(&configuration)?;
+        crate::hooks::validate(&configuration)?;
+
+        if configuration.minikube != self.configuration.minikube {
+            println!(
+                "{}",
+                "The [minikube] block changed. Restart 'kleinwuerfel up' to apply it.".yellow()
+            );
+        }
+
+        let globals_changed = configuration.default_values != self.configuration.default_values
+            || configuration.helm != self.configuration.helm
+            || configuration.images != self.configuration.images
+            || configuration.helm_chart_repo != self.configuration.helm_chart_repo;
+
+        let previous = self.helmcharts();
+        let current = configuration.helmchart.to_owned().unwrap_or_default();
+
+        for helmchart in &previous {
+            if !current.iter().any(|current| current.name == helmchart.name) {
+                self.stop_port_forward(&helmchart.name);
+            }
+        }
+
+        let changed = current
+            .iter()
+            .filter(|helmchart| globals_changed || !previous.contains(helmchart))
+            .map(|helmchart| helmchart.name.to_string())
+            .collect();
+
+        let hosts_changed = current
+            .iter()
+            .map(|helmchart| &helmchart.hosts)
+            .ne(previous.iter().map(|helmchart| &helmchart.hosts));
+
+        self.orchestrator = self.orchestrator.with_configuration(&configuration);
+        self.configuration = configuration;
+
+        if hosts_changed {
+            self.orchestrator.update_hosts()?;
+        }
+
+        Ok(changed)
+    }
+
+    fn start_port_forward(&mut self, helmchart: &Helmchart) -> anyhow::Result<()> {
+        check_local_ports(&[helmchart.to_owned()])?;
+
+        let port_forwards = self.orchestrator.start_port_forwards(helmchart);
+
+        self.forwards
+            .insert(helmchart.name.to_string(), all_forwards(&port_forwards));
+        self.port_forwards
+            .insert(helmchart.name.to_string(), port_forwards);
+
+        Ok(())
+    }
+
+    fn start_health_checks(&mut self, helmcharts: &[Helmchart]) {
+        for helmchart in helmcharts {
+            if helmchart.health_check.is_empty() {
+                continue;
+            }
+
+            let local_ports = self
+                .port_forwards
+                .get(&helmchart.name)
+                .map(|port_forwards| local_ports(port_forwards))
+                .unwrap_or_default();
+
+            self.pending_health_checks
+                .insert(helmchart.name.to_string());
+            self.orchestrator
+                .spawn_health_checks(helmchart, local_ports, &self.health_sender);
+        }
+    }
+
+    fn collect_health_results(&mut self, release: &str, results: Vec<HealthCheckResult>) {
+        if !self.pending_health_checks.remove(release) {
+            return;
+        }
+
+        self.health_results.insert(release.to_string(), results);
+
+        if self.pending_health_checks.is_empty() {
+            let mut results = std::mem::take(&mut self.health_results);
+
+            // in the order of the config file
+            print_results(
+                &self
+                    .helmcharts()
+                    .iter()
+                    .filter_map(|helmchart| results.remove(&helmchart.name))
+                    .flatten()
+                    .collect::<Vec<HealthCheckResult>>(),
+            );
+        }
+    }
+
+    fn stop_port_forward(&mut self, name: &str) {
+        self.forwards.remove(name);
+
+        for mut port_forward in self.port_forwards.remove(name).unwrap_or_default() {
+            port_forward.stop();
+        }
+    }
+
+    /// Reconnect port forwards whose kubectl exited, e.g. after a pod restart
+    fn supervise_port_forwards(&mut self) -> anyhow::Result<()> {
+        let mut reconnected = false;
+
+        for (name, port_forwards) in self.port_forwards.iter_mut() {
+            let mut changed = false;
+
+            for port_forward in port_forwards.iter_mut() {
+                changed |= self.orchestrator.supervise_port_forward(port_forward);
+            }
+
+            if changed {
+                self.forwards
+                    .insert(name.to_string(), all_forwards(port_forwards));
+                reconnected = true;
+            }
+        }
+
+        if reconnected {
+            self.report_forwards()?;
+        }
+
+        Ok(())
+    }
+
+    /// Print all current forwards and keep the forwards file up to date
+    fn report_forwards(&self) -> anyhow::Result<()> {
+        let forwards = self
+            .forwards
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<Vec<Forward>>();
+
+        print_forwards(&forwards);
+
+        if let Some(forwards_file) = &self.forwards_file {
+            write_forwards(forwards_file, &forwards)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Existing values files of the helm chart
+fn values_files(helmchart: &Helmchart) -> Vec<PathBuf> {
+    helmchart
+        .values_files()
+        .iter()
+        .filter_map(|values_file| fs::canonicalize(values_file).ok())
+        .collect()
+}
+
+fn collect_changed_paths(event: notify::Result<Event>, changed_paths: &mut BTreeSet<PathBuf>) {
+    if let Ok(event) = event {
+        if matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        ) {
+            changed_paths.extend(event.paths);
+        }
+    }
+}